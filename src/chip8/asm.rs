@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+use super::{Result, PROGRAM_START};
+
+// Assembler for the mnemonics used in the comments of the interpreter and printed by
+// the disassembler. Every line holds an optional `label:`, then an instruction or a
+// directive, and `;` starts a comment:
+//
+//     define SPEED 2
+//     start:  LD V0, SPEED
+//             LD I, ball
+//             DRW V0, V1, 2
+//             JP start
+//     ball:   sprite .##.....
+//             sprite .##.....
+//
+// Directives: `define NAME VALUE`, `db` and `dw` followed by comma separated bytes or
+// words, and `sprite` followed by rows of `#` and `.` (8 or 16 wide), one byte per 8 pixels.
+// Values are decimal, hexadecimal (0x or $), binary (0b), labels or constants, and can
+// be added and subtracted: `LD I, table + 4`
+
+#[derive(Clone, Debug, PartialEq)]
+enum Operand
+{
+    V(u8),
+    I,
+    // [I]
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    // Vx - Vy
+    Range(u8, u8),
+    // LONG value, XO-CHIP 16 bit address
+    Long(String),
+    Value(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Item
+{
+    Instruction(String, Vec<Operand>),
+    Bytes(Vec<String>),
+    Words(Vec<String>),
+    Raw(Vec<u8>),
+}
+
+impl Item
+{
+    fn size(&self) -> usize
+    {
+        match *self
+        {
+            Item::Instruction(_, ref operands) =>
+                if operands.iter().any(|o| matches!(o, Operand::Long(_))) { 4 } else { 2 },
+            Item::Bytes(ref values) => values.len(),
+            Item::Words(ref values) => values.len() * 2,
+            Item::Raw(ref bytes) => bytes.len(),
+        }
+    }
+}
+
+struct Statement
+{
+    line: usize,
+    item: Item,
+}
+
+struct Assembler
+{
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, String>,
+}
+
+// Assembles the source into a rom loaded at PROGRAM_START
+pub fn assemble(source: &str) -> Result<Vec<u8>>
+{
+    let mut assembler = Assembler { labels: HashMap::new(), constants: HashMap::new() };
+    let mut statements = Vec::new();
+    let mut address = PROGRAM_START;
+
+    // First pass: find the address of every label
+    for (n, text) in source.lines().enumerate()
+    {
+        let line = n + 1;
+        let statement = assembler.parse_line(text, address)
+                            .map_err(|e| format!("Line {}: {}", line, e))?;
+        if let Some(item) = statement
+        {
+            address += item.size();
+            statements.push(Statement { line, item });
+        }
+    }
+
+    // Second pass: encode with every label known
+    let mut rom = Vec::with_capacity(address - PROGRAM_START);
+    for statement in statements.iter()
+    {
+        assembler.encode(&statement.item, &mut rom)
+            .map_err(|e| format!("Line {}: {}", statement.line, e))?;
+    }
+    Ok(rom)
+}
+
+// Parses a number without sign: decimal, 0x or $ hexadecimal, 0b binary
+pub fn parse_number(text: &str) -> Option<i64>
+{
+    let lower = text.to_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('$'))
+    {
+        i64::from_str_radix(hex, 16).ok()
+    }
+    else if let Some(binary) = lower.strip_prefix("0b")
+    {
+        i64::from_str_radix(binary, 2).ok()
+    }
+    else
+    {
+        lower.parse().ok()
+    }
+}
+
+fn is_name(text: &str) -> bool
+{
+    let mut chars = text.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') &&
+        chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn parse_register(text: &str) -> Option<u8>
+{
+    let upper = text.trim().to_uppercase();
+    let digit = upper.strip_prefix('V')?;
+    if digit.len() == 1
+    {
+        u8::from_str_radix(digit, 16).ok()
+    }
+    else
+    {
+        None
+    }
+}
+
+// Pixels of a sprite row, `#` or `1` being on and `.` or `0` off
+fn parse_sprite_row(row: &str) -> std::result::Result<Vec<u8>, String>
+{
+    if row.is_empty() || !row.len().is_multiple_of(8)
+    {
+        return Err(format!("Sprite row {} must be 8 or 16 pixels wide", row));
+    }
+    let mut bytes = vec![0; row.len() / 8];
+    for (i, pixel) in row.chars().enumerate()
+    {
+        match pixel
+        {
+            '#' | '1' => bytes[i / 8] |= 0x80 >> (i % 8),
+            '.' | '0' => {}
+            _ => return Err(format!("Invalid pixel {} in sprite row, expected # or .", pixel)),
+        }
+    }
+    Ok(bytes)
+}
+
+fn parse_operand(text: &str) -> Operand
+{
+    let upper = text.to_uppercase();
+    if let Some(register) = parse_register(text)
+    {
+        return Operand::V(register);
+    }
+    if let Some((first, last)) = text.split_once('-')
+    {
+        if let (Some(x), Some(y)) = (parse_register(first), parse_register(last))
+        {
+            return Operand::Range(x, y);
+        }
+    }
+    if let Some(value) = upper.strip_prefix("LONG ")
+    {
+        return Operand::Long(text[text.len() - value.len()..].trim().to_string());
+    }
+    match upper.as_str()
+    {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::Dt,
+        "ST" => Operand::St,
+        "K" => Operand::K,
+        "F" => Operand::F,
+        "HF" => Operand::Hf,
+        "B" => Operand::B,
+        "R" => Operand::R,
+        _ => Operand::Value(text.to_string()),
+    }
+}
+
+impl Assembler
+{
+    fn parse_line(&mut self, text: &str, address: usize) -> std::result::Result<Option<Item>, String>
+    {
+        let mut text = text.split(';').next().unwrap_or("").trim();
+        if let Some((label, rest)) = text.split_once(':')
+        {
+            let label = label.trim();
+            if !is_name(label)
+            {
+                return Err(format!("Invalid label name {}", label));
+            }
+            if self.labels.insert(label.to_string(), address).is_some()
+            {
+                return Err(format!("Label {} is defined twice", label));
+            }
+            text = rest.trim();
+        }
+        if text.is_empty()
+        {
+            return Ok(None);
+        }
+        let (mnemonic, rest) = match text.split_once(char::is_whitespace)
+        {
+            Some((mnemonic, rest)) => (mnemonic.to_uppercase(), rest.trim()),
+            None => (text.to_uppercase(), ""),
+        };
+        let arguments: Vec<String> = if rest.is_empty()
+        {
+            Vec::new()
+        }
+        else
+        {
+            rest.split(',').map(|a| a.trim().to_string()).collect()
+        };
+        if arguments.iter().any(String::is_empty)
+        {
+            return Err("Empty operand".to_string());
+        }
+        let item = match mnemonic.as_str()
+        {
+            "DEFINE" =>
+            {
+                let (name, value) = match rest.split_once(char::is_whitespace)
+                {
+                    Some((name, value)) if is_name(name) => (name, value.trim()),
+                    _ => return Err("Usage: define NAME VALUE".to_string()),
+                };
+                if self.constants.insert(name.to_string(), value.to_string()).is_some()
+                {
+                    return Err(format!("Constant {} is defined twice", name));
+                }
+                return Ok(None);
+            }
+            "DB" => Item::Bytes(arguments),
+            "DW" => Item::Words(arguments),
+            "SPRITE" =>
+            {
+                let mut bytes = Vec::new();
+                for row in rest.split(|c: char| c == ',' || c.is_whitespace()).filter(|r| !r.is_empty())
+                {
+                    bytes.extend(parse_sprite_row(row)?);
+                }
+                if bytes.is_empty()
+                {
+                    return Err("Usage: sprite ROW, where ROW is made of # and .".to_string());
+                }
+                Item::Raw(bytes)
+            }
+            _ => Item::Instruction(mnemonic, arguments.iter().map(|a| parse_operand(a)).collect()),
+        };
+        Ok(Some(item))
+    }
+
+    // Evaluates a sum of numbers, labels and constants
+    fn evaluate(&self, expression: &str, depth: usize) -> std::result::Result<i64, String>
+    {
+        if depth > 16
+        {
+            return Err(format!("Constant {} refers to itself", expression));
+        }
+        let mut total = 0;
+        let mut sign = 1;
+        let mut term = String::new();
+        for c in expression.chars().chain(std::iter::once('+'))
+        {
+            if c == '+' || c == '-'
+            {
+                let term_text = term.trim();
+                if term_text.is_empty()
+                {
+                    // Unary sign
+                    if c == '-'
+                    {
+                        sign = -sign;
+                    }
+                    continue;
+                }
+                let value = if let Some(number) = parse_number(term_text)
+                {
+                    number
+                }
+                else if let Some(&address) = self.labels.get(term_text)
+                {
+                    address as i64
+                }
+                else if let Some(constant) = self.constants.get(term_text)
+                {
+                    self.evaluate(constant, depth + 1)?
+                }
+                else
+                {
+                    return Err(format!("Unknown value {}", term_text));
+                };
+                total += sign * value;
+                sign = if c == '-' { -1 } else { 1 };
+                term.clear();
+            }
+            else
+            {
+                term.push(c);
+            }
+        }
+        Ok(total)
+    }
+
+    fn value(&self, expression: &str, max: i64, what: &str) -> std::result::Result<u16, String>
+    {
+        let value = self.evaluate(expression, 0)?;
+        // Negative bytes are allowed as two's complement: ADD V0, -1
+        if max == 0xFF && (-0x80..0).contains(&value)
+        {
+            return Ok((value & 0xFF) as u16);
+        }
+        if value < 0 || value > max
+        {
+            return Err(format!("{} {} is out of range, it must be at most {:#X}", what, expression, max));
+        }
+        Ok(value as u16)
+    }
+
+    fn encode(&self, item: &Item, rom: &mut Vec<u8>) -> std::result::Result<(), String>
+    {
+        match *item
+        {
+            Item::Bytes(ref values) =>
+            {
+                for value in values.iter()
+                {
+                    rom.push(self.value(value, 0xFF, "Byte")? as u8);
+                }
+            }
+            Item::Words(ref values) =>
+            {
+                for value in values.iter()
+                {
+                    let word = self.value(value, 0xFFFF, "Word")?;
+                    rom.extend_from_slice(&word.to_be_bytes());
+                }
+            }
+            Item::Raw(ref bytes) => rom.extend_from_slice(bytes),
+            Item::Instruction(ref mnemonic, ref operands) =>
+            {
+                for word in self.encode_instruction(mnemonic, operands)?
+                {
+                    rom.extend_from_slice(&word.to_be_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_instruction(&self, mnemonic: &str, operands: &[Operand]) -> std::result::Result<Vec<u16>, String>
+    {
+        use self::Operand::*;
+        let addr = |e: &str| self.value(e, 0xFFF, "Address");
+        let byte = |e: &str| self.value(e, 0xFF, "Byte");
+        let nibble = |e: &str| self.value(e, 0xF, "Value");
+        let xy = |opcode: u16, x: u8, y: u8| opcode | (x as u16) << 8 | (y as u16) << 4;
+        let opcode = match (mnemonic, operands)
+        {
+            ("CLS", []) => 0x00E0,
+            ("RET", []) => 0x00EE,
+            ("SCD", [Value(n)]) => 0x00C0 | nibble(n)?,
+            ("SCU", [Value(n)]) => 0x00D0 | nibble(n)?,
+            ("SCR", []) => 0x00FB,
+            ("SCL", []) => 0x00FC,
+            ("EXIT", []) => 0x00FD,
+            ("LOW", []) => 0x00FE,
+            ("HIGH", []) => 0x00FF,
+            ("JP", [Value(a)]) => 0x1000 | addr(a)?,
+            ("JP", [V(0), Value(a)]) => 0xB000 | addr(a)?,
+            ("CALL", [Value(a)]) => 0x2000 | addr(a)?,
+            ("SE", [V(x), Value(k)]) => xy(0x3000, *x, 0) | byte(k)?,
+            ("SNE", [V(x), Value(k)]) => xy(0x4000, *x, 0) | byte(k)?,
+            ("SE", [V(x), V(y)]) => xy(0x5000, *x, *y),
+            ("SNE", [V(x), V(y)]) => xy(0x9000, *x, *y),
+            ("LD", [IndirectI, Range(x, y)]) => xy(0x5002, *x, *y),
+            ("LD", [Range(x, y), IndirectI]) => xy(0x5003, *x, *y),
+            ("LD", [V(x), Value(k)]) => xy(0x6000, *x, 0) | byte(k)?,
+            ("ADD", [V(x), Value(k)]) => xy(0x7000, *x, 0) | byte(k)?,
+            ("LD", [V(x), V(y)]) => xy(0x8000, *x, *y),
+            ("OR", [V(x), V(y)]) => xy(0x8001, *x, *y),
+            ("AND", [V(x), V(y)]) => xy(0x8002, *x, *y),
+            ("XOR", [V(x), V(y)]) => xy(0x8003, *x, *y),
+            ("ADD", [V(x), V(y)]) => xy(0x8004, *x, *y),
+            ("SUB", [V(x), V(y)]) => xy(0x8005, *x, *y),
+            // Without Vy the register is shifted in place whatever the shift quirk is
+            ("SHR", [V(x)]) => xy(0x8006, *x, *x),
+            ("SHR", [V(x), V(y)]) => xy(0x8006, *x, *y),
+            ("SUBN", [V(x), V(y)]) => xy(0x8007, *x, *y),
+            ("SHL", [V(x)]) => xy(0x800E, *x, *x),
+            ("SHL", [V(x), V(y)]) => xy(0x800E, *x, *y),
+            ("LD", [I, Value(a)]) => 0xA000 | addr(a)?,
+            ("LD", [I, Long(a)]) => return Ok(vec![0xF000, self.value(a, 0xFFFF, "Address")?]),
+            ("RND", [V(x), Value(k)]) => xy(0xC000, *x, 0) | byte(k)?,
+            ("DRW", [V(x), V(y), Value(n)]) => xy(0xD000, *x, *y) | nibble(n)?,
+            ("SKP", [V(x)]) => xy(0xE09E, *x, 0),
+            ("SKNP", [V(x)]) => xy(0xE0A1, *x, 0),
+            ("PLANE", [Value(n)]) => 0xF001 | self.value(n, 0xF, "Plane mask")? << 8,
+            ("AUDIO", []) => 0xF002,
+            ("LD", [V(x), Dt]) => xy(0xF007, *x, 0),
+            ("LD", [V(x), K]) => xy(0xF00A, *x, 0),
+            ("LD", [Dt, V(x)]) => xy(0xF015, *x, 0),
+            ("LD", [St, V(x)]) => xy(0xF018, *x, 0),
+            ("ADD", [I, V(x)]) => xy(0xF01E, *x, 0),
+            ("LD", [F, V(x)]) => xy(0xF029, *x, 0),
+            ("LD", [Hf, V(x)]) => xy(0xF030, *x, 0),
+            ("LD", [B, V(x)]) => xy(0xF033, *x, 0),
+            ("PITCH", [V(x)]) => xy(0xF03A, *x, 0),
+            ("LD", [IndirectI, V(x)]) => xy(0xF055, *x, 0),
+            ("LD", [V(x), IndirectI]) => xy(0xF065, *x, 0),
+            ("LD", [R, V(x)]) => xy(0xF075, *x, 0),
+            ("LD", [V(x), R]) => xy(0xF085, *x, 0),
+            _ if KNOWN_MNEMONICS.contains(&mnemonic) =>
+                return Err(format!("Invalid operands for {}", mnemonic)),
+            _ => return Err(format!("Unknown instruction {}", mnemonic)),
+        };
+        Ok(vec![opcode])
+    }
+}
+
+const KNOWN_MNEMONICS: [&str; 29] = ["CLS", "RET", "SCD", "SCU", "SCR", "SCL", "EXIT", "LOW", "HIGH",
+                                     "JP", "CALL", "SE", "SNE", "LD", "ADD", "OR", "AND", "XOR", "SUB",
+                                     "SHR", "SUBN", "SHL", "RND", "DRW", "SKP", "SKNP", "PLANE", "AUDIO",
+                                     "PITCH"];
+
+#[cfg(test)]
+mod tests
+{
+    use super::assemble;
+    use super::super::{Variant, PROGRAM_START};
+    use super::super::disasm::{self, LineKind};
+
+    // Every instruction the disassembler knows assembles back to the same opcode
+    #[test]
+    fn every_opcode_round_trip()
+    {
+        for opcode in 0..=0xFFFF
+        {
+            let instruction = match disasm::decode(opcode, Some(0x1234), Variant::XoChip)
+            {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            let rom = assemble(&instruction.mnemonic)
+                        .unwrap_or_else(|e| panic!("{}: {}", instruction.mnemonic, e));
+            let mut expected = opcode.to_be_bytes().to_vec();
+            if let Some(long_address) = instruction.long_address
+            {
+                expected.extend_from_slice(&long_address.to_be_bytes());
+            }
+            assert_eq!(rom, expected, "{}", instruction.mnemonic);
+        }
+    }
+
+    #[test]
+    fn listing_round_trip()
+    {
+        let source = "\
+            define SPEED 2
+            start:  LD V0, SPEED
+                    LD I, ball
+                    DRW V0, V1, 2
+                    CALL move
+                    JP start
+            move:   ADD V0, 1
+                    SE V0, 0x3C
+                    RET
+                    LD V0, 0
+                    RET
+            ball:   db 0x60, 0x60";
+        let rom = assemble(source).unwrap();
+        let listing: Vec<String> = disasm::recursive(&rom, PROGRAM_START, Variant::Chip8).iter().map(|line|
+        {
+            match line.kind
+            {
+                LineKind::Code(ref instruction) => instruction.mnemonic.clone(),
+                LineKind::Data(byte) => format!("db {}", byte),
+                LineKind::Unknown(opcode) => format!("dw {}", opcode),
+            }
+        }).collect();
+        assert_eq!(assemble(&listing.join("\n")).unwrap(), rom);
+    }
+}
@@ -0,0 +1,396 @@
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread;
+use super::{Result, ResultExt};
+use super::frontend::Buzzer;
+use super::scheduler::FRAMES_PER_SECOND;
+
+// Samples are mono signed 16 bit
+pub const SAMPLE_RATE: u32 = 44100;
+const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / FRAMES_PER_SECOND) as usize;
+// Bits of an XO-CHIP audio pattern
+const PATTERN_BITS: usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform
+{
+    Square,
+    Triangle,
+    Sawtooth,
+    Sine,
+}
+
+pub const WAVEFORM_NAMES: [&str; 4] = ["square", "triangle", "sawtooth", "sine"];
+
+impl Waveform
+{
+    pub fn from_name(name: &str) -> Option<Waveform>
+    {
+        match name.to_lowercase().as_str()
+        {
+            "square" => Some(Waveform::Square),
+            "triangle" => Some(Waveform::Triangle),
+            "sawtooth" => Some(Waveform::Sawtooth),
+            "sine" => Some(Waveform::Sine),
+            _ => None,
+        }
+    }
+
+    // Value between -1 and 1 at `phase`, which goes from 0 to 1 every period
+    fn sample(self, phase: f32) -> f32
+    {
+        match self
+        {
+            Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * phase - 1.0,
+            Waveform::Sine => (2.0 * PI * phase).sin(),
+        }
+    }
+}
+
+// Where the samples go
+pub trait AudioBackend
+{
+    fn write(&mut self, samples: &[i16]) -> Result<()>;
+}
+
+// Throws the sound away
+pub struct NullSink;
+
+impl AudioBackend for NullSink
+{
+    fn write(&mut self, _samples: &[i16]) -> Result<()>
+    {
+        Ok(())
+    }
+}
+
+// Records the sound to a WAV file, the sizes in the header are written when dropped
+pub struct WavSink
+{
+    file: BufWriter<File>,
+    samples: u32,
+}
+
+impl WavSink
+{
+    pub fn new(path: &str) -> Result<WavSink>
+    {
+        let mut sink = WavSink
+        {
+            file: BufWriter::new(File::create(path).chain_err(|| format!("Couldn't create {}", path))?),
+            samples: 0,
+        };
+        sink.write_header()?;
+        Ok(sink)
+    }
+
+    fn write_header(&mut self) -> Result<()>
+    {
+        let data_size = self.samples * 2;
+        let file = &mut self.file;
+        file.write_all(b"RIFF")?;
+        file.write_all(&(36 + data_size).to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?; // PCM
+        file.write_all(&1u16.to_le_bytes())?; // mono
+        file.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        file.write_all(&(SAMPLE_RATE * 2).to_le_bytes())?; // bytes per second
+        file.write_all(&2u16.to_le_bytes())?; // bytes per sample
+        file.write_all(&16u16.to_le_bytes())?; // bits per sample
+        file.write_all(b"data")?;
+        file.write_all(&data_size.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()>
+    {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+impl AudioBackend for WavSink
+{
+    fn write(&mut self, samples: &[i16]) -> Result<()>
+    {
+        for sample in samples
+        {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+}
+
+impl Drop for WavSink
+{
+    fn drop(&mut self)
+    {
+        if let Err(e) = self.finish()
+        {
+            eprintln!("Error finishing the WAV file: {}", e);
+        }
+    }
+}
+
+// Plays the sound piping raw samples (signed 16 bit little endian, mono, 44100Hz) to
+// the standard input of a player program, like `aplay -q -f S16_LE -r 44100 -c 1`.
+// The samples are written from another thread and dropped when the player falls
+// behind, so a slow player never slows the machine down
+pub struct CommandSink
+{
+    child: Child,
+    sender: Option<SyncSender<Vec<i16>>>,
+}
+
+pub const DEFAULT_AUDIO_COMMAND: &str = "aplay -q -f S16_LE -r 44100 -c 1";
+
+impl CommandSink
+{
+    pub fn new(command: &str) -> Result<CommandSink>
+    {
+        let mut words = command.split_whitespace();
+        let program = match words.next()
+        {
+            Some(program) => program,
+            None => bail!("Empty audio command"),
+        };
+        let mut child = Command::new(program)
+                            .args(words)
+                            .stdin(Stdio::piped())
+                            .stdout(Stdio::null())
+                            .spawn()
+                            .chain_err(|| format!("Couldn't start {}", program))?;
+        let mut stdin = match child.stdin.take()
+        {
+            Some(stdin) => stdin,
+            None => bail!("Couldn't open the input of the audio command"),
+        };
+        // A few frames of buffer
+        let (sender, receiver) = mpsc::sync_channel::<Vec<i16>>(4);
+        thread::spawn(move ||
+        {
+            for samples in receiver
+            {
+                let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+                if stdin.write_all(&bytes).is_err()
+                {
+                    break;
+                }
+            }
+        });
+        Ok(CommandSink { child, sender: Some(sender) })
+    }
+}
+
+impl AudioBackend for CommandSink
+{
+    fn write(&mut self, samples: &[i16]) -> Result<()>
+    {
+        if let Some(ref sender) = self.sender
+        {
+            if let Err(mpsc::TrySendError::Disconnected(_)) = sender.try_send(samples.to_vec())
+            {
+                self.sender = None;
+                bail!("The audio command stopped");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CommandSink
+{
+    fn drop(&mut self)
+    {
+        // Closing the pipe lets the player finish
+        self.sender = None;
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Tone played while the sound timer is running
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tone
+{
+    pub frequency: f32,
+    // From 0 to 1
+    pub volume: f32,
+    pub waveform: Waveform,
+}
+
+impl Default for Tone
+{
+    fn default() -> Tone
+    {
+        Tone { frequency: 440.0, volume: 0.25, waveform: Waveform::Square }
+    }
+}
+
+// Buzzer making the samples of one frame every time it's told whether it buzzes
+pub struct Audio<B: AudioBackend>
+{
+    backend: B,
+    tone: Tone,
+    phase: f32,
+    // XO-CHIP pattern and its rate, played instead of the tone
+    pattern: Option<([u8; 0x10], f32)>,
+    failed: bool,
+}
+
+impl<B: AudioBackend> Audio<B>
+{
+    pub fn new(backend: B, tone: Tone) -> Audio<B>
+    {
+        Audio { backend, tone, phase: 0.0, pattern: None, failed: false }
+    }
+
+    fn frame_samples(&mut self, buzzing: bool) -> Vec<i16>
+    {
+        if !buzzing
+        {
+            // Restart the wave from the start next time so every beep sounds the same
+            self.phase = 0.0;
+            return vec![0; SAMPLES_PER_FRAME];
+        }
+        let amplitude = self.tone.volume.clamp(0.0, 1.0) * i16::MAX as f32;
+        // The phase goes through the whole pattern, or a period of the tone
+        let step = match self.pattern
+        {
+            Some((_, rate)) => rate / PATTERN_BITS as f32 / SAMPLE_RATE as f32,
+            None => self.tone.frequency / SAMPLE_RATE as f32,
+        };
+        (0..SAMPLES_PER_FRAME).map(|_|
+        {
+            let sample = match self.pattern
+            {
+                Some((ref pattern, _)) =>
+                {
+                    let bit = (self.phase * PATTERN_BITS as f32) as usize % PATTERN_BITS;
+                    if (pattern[bit / 8] >> (7 - bit % 8)) & 1 != 0 { 1.0 } else { -1.0 }
+                }
+                None => self.tone.waveform.sample(self.phase),
+            };
+            self.phase = (self.phase + step).fract();
+            (sample * amplitude) as i16
+        }).collect()
+    }
+}
+
+impl<B: AudioBackend> Buzzer for Audio<B>
+{
+    fn set_buzzing(&mut self, buzzing: bool)
+    {
+        if self.failed
+        {
+            return;
+        }
+        let samples = self.frame_samples(buzzing);
+        if let Err(e) = self.backend.write(&samples)
+        {
+            // Reported once, the machine keeps running silently
+            eprintln!("Sound disabled: {}", e);
+            self.failed = true;
+        }
+    }
+
+    fn set_pattern(&mut self, pattern: &[u8; 0x10], rate: f32)
+    {
+        self.pattern = Some((*pattern, rate));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::fs;
+    use super::{Audio, AudioBackend, Tone, WavSink, Waveform, SAMPLES_PER_FRAME};
+    use super::super::{Chip8, Quirks, Result, Variant};
+    use super::super::frontend::{Buzzer, Composite, NullKeypad};
+    use super::super::headless::HeadlessDisplay;
+    use super::super::runner::Runner;
+    use super::super::scheduler::VirtualClock;
+
+    // Keeps the samples to look at them
+    struct Samples(Vec<i16>);
+
+    impl AudioBackend for Samples
+    {
+        fn write(&mut self, samples: &[i16]) -> Result<()>
+        {
+            self.0.extend_from_slice(samples);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn plays_the_pattern()
+    {
+        let mut audio = Audio::new(Samples(Vec::new()), Tone::default());
+        let mut pattern = [0; 0x10];
+        pattern[0] = 0xFF;
+        audio.set_pattern(&pattern, 4000.0);
+        audio.set_buzzing(true);
+        // 44100 / 4000 samples per bit, the first 8 bits are set
+        let samples = &audio.backend.0;
+        assert_eq!(samples.len(), 735);
+        assert!(samples[..89].iter().all(|s| *s > 0));
+        assert!(samples[89..735].iter().all(|s| *s < 0));
+    }
+
+    #[test]
+    fn waveform_names()
+    {
+        assert_eq!(Waveform::from_name("Sine"), Some(Waveform::Sine));
+        assert_eq!(Waveform::from_name("SQUARE"), Some(Waveform::Square));
+        assert_eq!(Waveform::from_name("noise"), None);
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32
+    {
+        u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+    }
+
+    #[test]
+    fn records_the_sound_timer_to_wav()
+    {
+        let path = std::env::temp_dir().join(format!("chip8_buzzer_{}.wav", std::process::id()));
+        let path_name = path.to_string_lossy().into_owned();
+        {
+            // LD V0, 30 ; LD ST, V0 ; JP 0x204
+            let rom = [0x60, 0x1E, 0xF0, 0x18, 0x12, 0x04];
+            let mut chip = Chip8::new(Some(&rom), Variant::Chip8, Quirks::default()).unwrap();
+            let buzzer = Audio::new(WavSink::new(&path_name).unwrap(), Tone::default());
+            let mut frontend = Composite { display: HeadlessDisplay::new(60), keypad: NullKeypad, buzzer };
+            Runner::new().run_with_clock(&mut chip, &mut frontend, &mut VirtualClock::new()).unwrap();
+        }
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let data_size = 60 * SAMPLES_PER_FRAME as u32 * 2;
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32_at(&data, 4), 36 + data_size);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&data, 16), 16);
+        // PCM, mono, 44100Hz, 88200 bytes per second, 2 bytes per sample, 16 bits
+        assert_eq!(data[20..24], [1, 0, 1, 0]);
+        assert_eq!(u32_at(&data, 24), 44100);
+        assert_eq!(u32_at(&data, 28), 88200);
+        assert_eq!(data[32..36], [2, 0, 16, 0]);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(u32_at(&data, 40), data_size);
+        assert_eq!(data.len(), 44 + data_size as usize);
+        // The timer runs out 30 frames after being set, in the first frame
+        let samples: Vec<i16> = data[44..].chunks(2).map(|s| i16::from_le_bytes([s[0], s[1]])).collect();
+        assert!(samples[..29 * SAMPLES_PER_FRAME].iter().all(|s| *s != 0));
+        assert!(samples[29 * SAMPLES_PER_FRAME..].iter().all(|s| *s == 0));
+    }
+}
@@ -0,0 +1,178 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use super::{Chip8, Result, ResultExt};
+use super::disasm::{self, Line, LineKind};
+use super::fault::Fault;
+use super::frontend::Screen;
+
+// Instructions of the disassembly shown before and after the faulting one
+const DISASSEMBLY_CONTEXT: usize = 8;
+
+// V registers on two lines followed by the other registers
+pub fn format_registers(chip: &Chip8) -> String
+{
+    let mut text = String::new();
+    for (i, value) in chip.v_registers.iter().enumerate()
+    {
+        let separator = if i == 7 || i == 0xF { '\n' } else { ' ' };
+        let _ = write!(text, "V{:X}={:02X}{}", i, value, separator);
+    }
+    let _ = write!(text, "PC={:04X} I={:04X} SP={:X} DT={:02X} ST={:02X}",
+                    chip.program_counter, chip.index, chip.stack_pointer, chip.dt, chip.st);
+    text
+}
+
+// Return addresses from the innermost subroutine
+pub fn format_stack(chip: &Chip8) -> String
+{
+    if chip.stack_pointer == 0
+    {
+        return "Stack is empty".to_string();
+    }
+    // The stack grows from 1, each entry is the address the RET goes back to
+    (1..=chip.stack_pointer).rev()
+        .map(|depth| format!("#{} {:#06X}", depth, chip.stack[depth]))
+        .collect::<Vec<_>>().join("\n")
+}
+
+// Last instructions executed, the oldest first
+fn format_history(chip: &Chip8) -> String
+{
+    if chip.history.is_empty()
+    {
+        return "No instructions executed".to_string();
+    }
+    chip.history.iter().map(|&(address, opcode)|
+    {
+        let next = chip.mem.get(address + 2..address + 4).map(|word| (word[0] as u16) << 8 | word[1] as u16);
+        let kind = match disasm::decode(opcode, next, chip.variant)
+        {
+            Some(instruction) => LineKind::Code(instruction),
+            None => LineKind::Unknown(opcode),
+        };
+        disasm::format_line(&Line { address, kind })
+    }).collect::<Vec<_>>().join("\n")
+}
+
+fn format_disassembly(chip: &Chip8, pc: usize) -> String
+{
+    disasm::window(&chip.mem, pc, DISASSEMBLY_CONTEXT, DISASSEMBLY_CONTEXT, chip.variant).iter().map(|line|
+    {
+        let marker = if line.address == pc { "=>" } else { "  " };
+        format!("{} {}", marker, disasm::format_line(line))
+    }).collect::<Vec<_>>().join("\n")
+}
+
+// 16 bytes per line, runs of lines equal to the previous one are shown as a *
+fn hexdump(mem: &[u8]) -> String
+{
+    let mut lines = Vec::new();
+    let mut previous: Option<&[u8]> = None;
+    for (row, bytes) in mem.chunks(0x10).enumerate()
+    {
+        if previous == Some(bytes)
+        {
+            if lines.last().map(String::as_str) != Some("*")
+            {
+                lines.push("*".to_string());
+            }
+            continue;
+        }
+        previous = Some(bytes);
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let ascii: String = bytes.iter().map(|&byte| if byte.is_ascii_graphic() { byte as char } else { '.' }).collect();
+        lines.push(format!("{:04X}: {}  {}", row * 0x10, hex.join(" "), ascii));
+    }
+    lines.join("\n")
+}
+
+// One character per pixel, by the planes it is set in
+fn format_screen(screen: &Screen) -> String
+{
+    const PIXELS: [char; 4] = ['.', '#', '+', '*'];
+    (0..screen.height()).map(|y|
+    {
+        (0..screen.width()).map(|x| PIXELS[screen.get(x, y) as usize & 3]).collect::<String>()
+    }).collect::<Vec<_>>().join("\n")
+}
+
+// Everything needed to understand a crash, to attach to bug reports
+pub fn report(chip: &Chip8, fault: &Fault, rom_name: &str) -> String
+{
+    let sections = [
+        ("Registers", format_registers(chip)),
+        ("Call stack", format_stack(chip)),
+        ("Last instructions", format_history(chip)),
+        ("Disassembly", format_disassembly(chip, fault.pc)),
+        ("Memory", hexdump(&chip.mem)),
+        ("Screen", format_screen(chip.screen())),
+    ];
+    let mut text = format!("Crash report of {}\n{}\nMode: {:?}\nQuirks: {:?}\n",
+                            rom_name, fault, chip.variant, chip.quirks);
+    for (title, body) in sections.iter()
+    {
+        let _ = write!(text, "\n{}:\n{}\n", title, body);
+    }
+    text
+}
+
+// ROM.crash next to the rom
+pub fn report_path(rom_name: &str) -> PathBuf
+{
+    let mut path = PathBuf::from(rom_name).into_os_string();
+    path.push(".crash");
+    PathBuf::from(path)
+}
+
+pub fn write_report(path: &Path, chip: &Chip8, fault: &Fault, rom_name: &str) -> Result<()>
+{
+    fs::write(path, report(chip, fault, rom_name))
+        .chain_err(|| format!("Couldn't write crash report {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::fs;
+    use super::{report, report_path, write_report};
+    use super::super::{Chip8, Quirks, Variant};
+
+    #[test]
+    fn report_of_a_fault()
+    {
+        // LD V3, 0x42 ; CALL 0x206 ; JP 0x204 ; CALL 0x20A ; JP 0x208 ; an invalid opcode
+        let rom = [0x63, 0x42, 0x22, 0x06, 0x12, 0x04, 0x22, 0x0A, 0x12, 0x08, 0x51, 0x21];
+        let mut chip = Chip8::new(Some(&rom), Variant::Chip8, Quirks::default()).unwrap();
+        let fault = (0..10).find_map(|_| chip.step().err()).unwrap();
+        let text = report(&chip, &fault, "roms/test.ch8");
+        let expected = [
+            "Crash report of roms/test.ch8\nInvalid opcode executing instruction 0x5121 at 0x020A\n",
+            "\nRegisters:\nV0=00 V1=00 V2=00 V3=42 V4=00 V5=00 V6=00 V7=00\n\
+             V8=00 V9=00 VA=00 VB=00 VC=00 VD=00 VE=00 VF=00\n\
+             PC=020A I=0000 SP=2 DT=00 ST=00\n",
+            "\nCall stack:\n#2 0x0208\n#1 0x0204\n",
+            "\nLast instructions:\n\
+             0x0200  6342      LD V3, 0x42\n\
+             0x0202  2206      CALL 0x206\n\
+             0x0206  220A      CALL 0x20A\n",
+            "\nDisassembly:\n   0x01FA  0000      DW 0x0000\n",
+            "   0x0208  1208      JP 0x208\n\
+             => 0x020A  5121      DW 0x5121\n\
+             \x20  0x020C  0000      DW 0x0000\n",
+            "   0x021A  0000      DW 0x0000\n\nMemory:\n",
+            "\n0200: 63 42 22 06 12 04 22 0A 12 08 51 21 00 00 00 00  cB\"...\"...Q!....\n",
+        ];
+        for section in expected.iter()
+        {
+            assert!(text.contains(section), "{}", section);
+        }
+
+        let path = report_path(&std::env::temp_dir().join(format!("chip8_crash_{}", std::process::id()))
+                                    .to_string_lossy());
+        assert!(path.to_string_lossy().ends_with(".crash"));
+        write_report(&path, &chip, &fault, "roms/test.ch8").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), text);
+        fs::remove_file(&path).unwrap();
+    }
+}
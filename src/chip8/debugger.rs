@@ -0,0 +1,479 @@
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use super::Chip8;
+use super::crash;
+use super::disasm;
+use super::fault::Fault;
+use super::watchpoint::{Watchpoint, RegisterSnapshot};
+
+const HELP: &str = "\
+break ADDR (b)      set a breakpoint at ADDR
+delete ADDR (d)     remove the breakpoint at ADDR
+breakpoints (bl)    list the breakpoints
+step (s)            execute one instruction
+next (n)            execute one instruction, running CALLs until they return
+finish (f)          run until the current subroutine returns
+continue (c)        run until a breakpoint is hit
+skip (k)            jump over the instruction at the PC without executing it,
+                    to get past a fault
+pause (p)           stop the execution
+regs (r)            show the registers
+stack (bt)          show the call stack
+mem ADDR [LEN] (x)  show LEN bytes of memory starting at ADDR
+list [ADDR] (l)     disassemble the instructions around ADDR, the PC by default
+watch ADDR [r|w|rw] [== VALUE] (w)
+                    stop when an instruction reads or writes the byte at ADDR,
+                    only when the byte is VALUE if given
+watch Vx|I [== VALUE]
+                    stop when the register changes, only when it becomes VALUE if given
+unwatch N (uw)      remove the watchpoint number N
+watchpoints (wl)    list the watchpoints
+help (h)            show this help";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StepMode
+{
+    // Run until a breakpoint
+    Run,
+    // Stop after the next instruction
+    Step,
+    // Stop when the instruction after the CALL at return_to is reached at the same depth
+    Over { return_to: usize, depth: usize },
+    // Stop when the stack gets shallower than depth
+    Out { depth: usize },
+}
+
+// Debugger controlled by commands typed on stdin, they are read in another thread so
+// the window keeps responding while the machine is stopped
+pub struct Debugger
+{
+    breakpoints: BTreeSet<usize>,
+    paused: bool,
+    mode: StepMode,
+    // Set when resuming from a breakpoint so it doesn't trigger again right away
+    resuming: bool,
+    watchpoints: Vec<Watchpoint>,
+    // State before the instruction being executed, checked by the watchpoints
+    registers_before: Option<RegisterSnapshot>,
+    instruction_address: usize,
+    input: Receiver<String>,
+}
+
+// Numbers are always hexadecimal, optionally prefixed by 0x or $
+pub fn parse_number(text: &str) -> Option<usize>
+{
+    let digits = text.trim().trim_start_matches("0x").trim_start_matches("0X").trim_start_matches('$');
+    usize::from_str_radix(digits, 16).ok()
+}
+
+fn prompt()
+{
+    print!("(c8db) ");
+    let _ = io::stdout().flush();
+}
+
+impl Debugger
+{
+    // Starts the debugger with the machine stopped before its first instruction
+    pub fn new() -> Debugger
+    {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move ||
+        {
+            let stdin = io::stdin();
+            for line in stdin.lock().lines()
+            {
+                match line
+                {
+                    Ok(line) => if sender.send(line).is_err() { break; },
+                    Err(_) => break,
+                }
+            }
+        });
+        Debugger::with_input(receiver)
+    }
+
+    // Same as new, with the commands read from `input` instead of stdin
+    pub fn with_input(input: Receiver<String>) -> Debugger
+    {
+        println!("Debugger started, type help for the list of commands");
+        prompt();
+        Debugger
+        {
+            breakpoints: BTreeSet::new(),
+            paused: true,
+            mode: StepMode::Run,
+            resuming: false,
+            watchpoints: Vec::new(),
+            registers_before: None,
+            instruction_address: 0,
+            input,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool
+    {
+        self.paused
+    }
+
+    // Stops the machine, showing where
+    fn pause(&mut self, chip: &Chip8, reason: &str)
+    {
+        self.paused = true;
+        self.mode = StepMode::Run;
+        println!();
+        println!("{} at {:#06X}", reason, chip.program_counter);
+        self.print_registers(chip);
+    }
+
+    // Stops where the machine faulted, before the faulting instruction. Resuming runs
+    // it again, so it faults again unless something was changed
+    pub fn stop_at_fault(&mut self, chip: &Chip8, fault: &Fault)
+    {
+        self.pause(chip, &fault.kind.to_string());
+        println!("The instruction faults again when resumed, use skip to jump over it");
+        prompt();
+    }
+
+    // Executes the commands typed since the last call
+    pub fn poll(&mut self, chip: &mut Chip8)
+    {
+        loop
+        {
+            match self.input.try_recv()
+            {
+                Ok(line) =>
+                {
+                    self.execute(chip, &line);
+                    if self.paused
+                    {
+                        prompt();
+                    }
+                }
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) =>
+                {
+                    // stdin closed, nothing can resume the machine anymore so the
+                    // program is left running freely
+                    if self.paused || !self.breakpoints.is_empty() || !self.watchpoints.is_empty()
+                    {
+                        println!("Debugger input closed, detaching");
+                    }
+                    self.breakpoints.clear();
+                    self.watchpoints.clear();
+                    self.paused = false;
+                    self.mode = StepMode::Run;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn resume(&mut self, mode: StepMode)
+    {
+        self.paused = false;
+        self.resuming = true;
+        self.mode = mode;
+    }
+
+    fn execute(&mut self, chip: &mut Chip8, line: &str)
+    {
+        let mut words = line.split_whitespace();
+        let command = match words.next()
+        {
+            Some(command) => command,
+            None => return,
+        };
+        let arguments: Vec<&str> = words.collect();
+        let address = arguments.first().and_then(|a| parse_number(a));
+        match command
+        {
+            "break" | "b" => match address
+            {
+                Some(address) =>
+                {
+                    self.breakpoints.insert(address);
+                    println!("Breakpoint at {:#06X}", address);
+                }
+                None => println!("Usage: break ADDR"),
+            },
+            "delete" | "d" => match address
+            {
+                Some(address) if self.breakpoints.remove(&address) =>
+                    println!("Removed breakpoint at {:#06X}", address),
+                _ => println!("No breakpoint there"),
+            },
+            "breakpoints" | "bl" =>
+            {
+                for address in self.breakpoints.iter()
+                {
+                    println!("{:#06X}", address);
+                }
+            }
+            "step" | "s" => self.resume(StepMode::Step),
+            "next" | "n" =>
+            {
+                // Only CALLs are stepped over, anything else is a plain step
+                if chip.fetch_instruction() & 0xF000 == 0x2000
+                {
+                    self.resume(StepMode::Over { return_to: chip.program_counter + 2,
+                                                    depth: chip.stack_pointer });
+                }
+                else
+                {
+                    self.resume(StepMode::Step);
+                }
+            }
+            "finish" | "f" =>
+            {
+                if chip.stack_pointer == 0
+                {
+                    println!("Not inside a subroutine");
+                }
+                else
+                {
+                    self.resume(StepMode::Out { depth: chip.stack_pointer });
+                }
+            }
+            "continue" | "c" => self.resume(StepMode::Run),
+            "skip" | "k" =>
+            {
+                let address = chip.program_counter;
+                chip.skip_instruction();
+                println!("Skipped the instruction at {:#06X}", address);
+                self.print_registers(chip);
+            }
+            "pause" | "p" => if !self.paused { self.pause(chip, "Paused") },
+            "regs" | "r" => self.print_registers(chip),
+            "stack" | "bt" => self.print_stack(chip),
+            "mem" | "x" => match address
+            {
+                Some(address) =>
+                {
+                    let len = arguments.get(1).and_then(|l| parse_number(l)).unwrap_or(0x10);
+                    println!("{}", format_memory(chip, address, len));
+                }
+                None => println!("Usage: mem ADDR [LEN]"),
+            },
+            "list" | "l" =>
+            {
+                let address = address.unwrap_or(chip.program_counter);
+                for line in disasm::window(&chip.mem, address, 5, 5, chip.variant)
+                {
+                    let marker = if line.address == chip.program_counter { "=>" } else { "  " };
+                    println!("{} {}", marker, disasm::format_line(&line));
+                }
+            }
+            "watch" | "w" => match Watchpoint::parse(&arguments)
+            {
+                Some(watchpoint) =>
+                {
+                    println!("Watchpoint {}: {}", self.watchpoints.len(), watchpoint);
+                    self.watchpoints.push(watchpoint);
+                }
+                None => println!("Usage: watch ADDR [r|w|rw] [== VALUE] or watch Vx|I [== VALUE]"),
+            },
+            "unwatch" | "uw" => match arguments.first().and_then(|n| n.parse::<usize>().ok())
+            {
+                Some(n) if n < self.watchpoints.len() =>
+                    println!("Removed watchpoint {}", self.watchpoints.remove(n)),
+                _ => println!("No watchpoint with that number"),
+            },
+            "watchpoints" | "wl" =>
+            {
+                for (n, watchpoint) in self.watchpoints.iter().enumerate()
+                {
+                    println!("{}: {}", n, watchpoint);
+                }
+            }
+            "help" | "h" => println!("{}", HELP),
+            _ => println!("Unknown command {}, type help for the list of commands", command),
+        }
+    }
+
+    // Returns whether the next instruction can be executed, stopping on breakpoints
+    pub fn before_step(&mut self, chip: &mut Chip8) -> bool
+    {
+        if self.paused
+        {
+            return false;
+        }
+        if !self.resuming && self.breakpoints.contains(&chip.program_counter)
+        {
+            self.pause(chip, "Breakpoint");
+            prompt();
+            return false;
+        }
+        chip.set_memory_tracking(self.watchpoints.iter().any(Watchpoint::watches_memory));
+        self.registers_before = Some(RegisterSnapshot::of(chip));
+        self.instruction_address = chip.program_counter;
+        true
+    }
+
+    // Stops when the current step command is done
+    pub fn after_step(&mut self, chip: &Chip8)
+    {
+        self.resuming = false;
+        if let Some(before) = self.registers_before.take()
+        {
+            let hit = self.watchpoints.iter().enumerate()
+                        .find_map(|(n, watchpoint)| watchpoint.check(chip, &before)
+                                                        .map(|what| (n, what)));
+            if let Some((n, what)) = hit
+            {
+                let reason = format!("Watchpoint {}: instruction at {:#06X} {}, stopped",
+                                        n, self.instruction_address, what);
+                self.pause(chip, &reason);
+                prompt();
+                return;
+            }
+        }
+        let done = match self.mode
+        {
+            StepMode::Run => false,
+            StepMode::Step => true,
+            StepMode::Over { return_to, depth } =>
+                chip.program_counter == return_to && chip.stack_pointer == depth,
+            StepMode::Out { depth } => chip.stack_pointer < depth,
+        };
+        if done
+        {
+            self.pause(chip, "Stopped");
+            prompt();
+        }
+    }
+
+    fn print_registers(&self, chip: &Chip8)
+    {
+        println!("{}", crash::format_registers(chip));
+        if let Some(line) = disasm::window(&chip.mem, chip.program_counter, 0, 0, chip.variant).first()
+        {
+            println!("next: {}", disasm::format_line(line));
+        }
+        if let Some(register) = chip.waiting_for_key
+        {
+            println!("Waiting for a key to store in V{:X}", register);
+        }
+    }
+
+    fn print_stack(&self, chip: &Chip8)
+    {
+        println!("{}", crash::format_stack(chip));
+    }
+}
+
+// Hex dump of `len` bytes of memory from `address`, 16 per line
+fn format_memory(chip: &Chip8, address: usize, len: usize) -> String
+{
+    let end = (address + len).min(chip.mem.len());
+    if address >= end
+    {
+        return "Address out of memory".to_string();
+    }
+    chip.mem[address..end].chunks(0x10).enumerate().map(|(row, bytes)|
+    {
+        let mut line = format!("{:04X}:", address + row * 0x10);
+        for byte in bytes
+        {
+            let _ = write!(line, " {:02X}", byte);
+        }
+        line
+    }).collect::<Vec<_>>().join("\n")
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::sync::mpsc::{self, Sender};
+    use super::{format_memory, parse_number, Debugger};
+    use super::super::{Chip8, Quirks, Variant};
+
+    fn start(rom: &[u8]) -> (Chip8, Debugger, Sender<String>)
+    {
+        let chip = Chip8::new(Some(rom), Variant::Chip8, Quirks::default()).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        (chip, Debugger::with_input(receiver), sender)
+    }
+
+    // Types `command` and runs up to `steps` instructions the way the runner does
+    fn run(debugger: &mut Debugger, chip: &mut Chip8, sender: &Sender<String>, command: &str, steps: usize)
+    {
+        sender.send(command.to_string()).unwrap();
+        debugger.poll(chip);
+        for _ in 0..steps
+        {
+            if debugger.before_step(chip)
+            {
+                chip.step().unwrap();
+                debugger.after_step(chip);
+            }
+        }
+    }
+
+    #[test]
+    fn numbers_are_hexadecimal()
+    {
+        assert_eq!(parse_number("200"), Some(0x200));
+        assert_eq!(parse_number("0x2a"), Some(0x2A));
+        assert_eq!(parse_number("$2A"), Some(0x2A));
+        assert_eq!(parse_number("V1"), None);
+    }
+
+    #[test]
+    fn breakpoints_and_stepping()
+    {
+        // LD V0, 1 ; LD V1, 2 ; LD V2, 3 ; JP 0x206
+        let (mut chip, mut debugger, sender) = start(&[0x60, 0x01, 0x61, 0x02, 0x62, 0x03, 0x12, 0x06]);
+        run(&mut debugger, &mut chip, &sender, "b 204", 10);
+        assert_eq!(chip.program_counter, 0x200);
+        run(&mut debugger, &mut chip, &sender, "c", 10);
+        assert!(debugger.is_paused());
+        assert_eq!(chip.program_counter, 0x204);
+        assert_eq!(chip.v_registers[..3], [1, 2, 0]);
+        run(&mut debugger, &mut chip, &sender, "s", 10);
+        assert!(debugger.is_paused());
+        assert_eq!(chip.program_counter, 0x206);
+        assert_eq!(chip.v_registers[2], 3);
+        run(&mut debugger, &mut chip, &sender, "c", 10);
+        assert!(!debugger.is_paused());
+        // Removed breakpoints don't stop the machine anymore
+        chip.program_counter = 0x200;
+        run(&mut debugger, &mut chip, &sender, "d 204", 10);
+        assert_eq!(chip.program_counter, 0x206);
+    }
+
+    #[test]
+    fn next_and_finish()
+    {
+        // CALL 0x206 ; LD V0, 1 ; JP 0x204 ; LD V1, 5 ; RET
+        let rom = [0x22, 0x06, 0x60, 0x01, 0x12, 0x04, 0x61, 0x05, 0x00, 0xEE];
+        let (mut chip, mut debugger, sender) = start(&rom);
+        run(&mut debugger, &mut chip, &sender, "n", 10);
+        assert!(debugger.is_paused());
+        assert_eq!((chip.program_counter, chip.v_registers[1]), (0x202, 5));
+
+        let (mut chip, mut debugger, sender) = start(&rom);
+        run(&mut debugger, &mut chip, &sender, "s", 10);
+        assert_eq!((chip.program_counter, chip.stack_pointer), (0x206, 1));
+        run(&mut debugger, &mut chip, &sender, "f", 10);
+        assert!(debugger.is_paused());
+        assert_eq!((chip.program_counter, chip.stack_pointer), (0x202, 0));
+    }
+
+    #[test]
+    fn memory_and_skip()
+    {
+        let rom: Vec<u8> = (0..0x12).collect();
+        let (mut chip, mut debugger, sender) = start(&rom);
+        assert_eq!(format_memory(&chip, 0x200, 0x12),
+                    "0200: 00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F\n0210: 10 11");
+        assert_eq!(format_memory(&chip, 0xFFE, 4), "0FFE: 00 00");
+        assert_eq!(format_memory(&chip, 0x1000, 1), "Address out of memory");
+        run(&mut debugger, &mut chip, &sender, "k", 10);
+        assert!(debugger.is_paused());
+        assert_eq!(chip.program_counter, 0x202);
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::{BTreeSet, VecDeque};
+use super::Variant;
+
+// What an instruction does to the program counter, used to follow the code
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flow
+{
+    // Continues with the next instruction
+    Next,
+    // Can skip the next instruction
+    Skip,
+    Jump(usize),
+    Call(usize),
+    // Jump to an address only known when running (Bnnn)
+    Computed,
+    Return,
+    // Never continues (00FD)
+    Stop,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction
+{
+    pub opcode: u16,
+    // 2 bytes, or 4 for the XO-CHIP F000 nnnn long load
+    pub len: usize,
+    // Address word following F000
+    pub long_address: Option<u16>,
+    pub mnemonic: String,
+    pub flow: Flow,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LineKind
+{
+    Code(Instruction),
+    // Bytes that aren't reached by the code, like sprites
+    Data(u8),
+    // Instruction the interpreter doesn't understand
+    Unknown(u16),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line
+{
+    pub address: usize,
+    pub kind: LineKind,
+}
+
+// Decodes one opcode, `next` being the word after it (needed by F000 nnnn).
+// Returns None if the variant doesn't have that instruction
+pub fn decode(opcode: u16, next: Option<u16>, variant: Variant) -> Option<Instruction>
+{
+    let nibbles = (((opcode & 0xF000) >> 12) as u8,
+                    ((opcode & 0x0F00) >> 8) as u8,
+                    ((opcode & 0x00F0) >> 4) as u8,
+                    (opcode & 0x000F) as u8);
+    let addr = (opcode & 0x0FFF) as usize;
+    let kk = (opcode & 0x00FF) as u8;
+    let schip = variant >= Variant::SuperChip;
+    let xochip = variant == Variant::XoChip;
+    let mut long_address = None;
+    let (mnemonic, flow) = match nibbles
+    {
+        (0, 0, 0xC, n) if schip => (format!("SCD {}", n), Flow::Next),
+        (0, 0, 0xD, n) if xochip => (format!("SCU {}", n), Flow::Next),
+        (0, 0, 0xE, 0) => ("CLS".to_string(), Flow::Next),
+        (0, 0, 0xE, 0xE) => ("RET".to_string(), Flow::Return),
+        (0, 0, 0xF, 0xB) if schip => ("SCR".to_string(), Flow::Next),
+        (0, 0, 0xF, 0xC) if schip => ("SCL".to_string(), Flow::Next),
+        (0, 0, 0xF, 0xD) if schip => ("EXIT".to_string(), Flow::Stop),
+        (0, 0, 0xF, 0xE) if schip => ("LOW".to_string(), Flow::Next),
+        (0, 0, 0xF, 0xF) if schip => ("HIGH".to_string(), Flow::Next),
+        (1, _, _, _) => (format!("JP {:#05X}", addr), Flow::Jump(addr)),
+        (2, _, _, _) => (format!("CALL {:#05X}", addr), Flow::Call(addr)),
+        (3, x, _, _) => (format!("SE V{:X}, {:#04X}", x, kk), Flow::Skip),
+        (4, x, _, _) => (format!("SNE V{:X}, {:#04X}", x, kk), Flow::Skip),
+        (5, x, y, 0) => (format!("SE V{:X}, V{:X}", x, y), Flow::Skip),
+        (5, x, y, 2) if xochip => (format!("LD [I], V{:X} - V{:X}", x, y), Flow::Next),
+        (5, x, y, 3) if xochip => (format!("LD V{:X} - V{:X}, [I]", x, y), Flow::Next),
+        (6, x, _, _) => (format!("LD V{:X}, {:#04X}", x, kk), Flow::Next),
+        (7, x, _, _) => (format!("ADD V{:X}, {:#04X}", x, kk), Flow::Next),
+        (8, x, y, 0) => (format!("LD V{:X}, V{:X}", x, y), Flow::Next),
+        (8, x, y, 1) => (format!("OR V{:X}, V{:X}", x, y), Flow::Next),
+        (8, x, y, 2) => (format!("AND V{:X}, V{:X}", x, y), Flow::Next),
+        (8, x, y, 3) => (format!("XOR V{:X}, V{:X}", x, y), Flow::Next),
+        (8, x, y, 4) => (format!("ADD V{:X}, V{:X}", x, y), Flow::Next),
+        (8, x, y, 5) => (format!("SUB V{:X}, V{:X}", x, y), Flow::Next),
+        (8, x, y, 6) => (format!("SHR V{:X}, V{:X}", x, y), Flow::Next),
+        (8, x, y, 7) => (format!("SUBN V{:X}, V{:X}", x, y), Flow::Next),
+        (8, x, y, 0xE) => (format!("SHL V{:X}, V{:X}", x, y), Flow::Next),
+        (9, x, y, 0) => (format!("SNE V{:X}, V{:X}", x, y), Flow::Skip),
+        (0xA, _, _, _) => (format!("LD I, {:#05X}", addr), Flow::Next),
+        (0xB, _, _, _) => (format!("JP V0, {:#05X}", addr), Flow::Computed),
+        (0xC, x, _, _) => (format!("RND V{:X}, {:#04X}", x, kk), Flow::Next),
+        (0xD, x, y, n) => (format!("DRW V{:X}, V{:X}, {}", x, y, n), Flow::Next),
+        (0xE, x, 0x9, 0xE) => (format!("SKP V{:X}", x), Flow::Skip),
+        (0xE, x, 0xA, 0x1) => (format!("SKNP V{:X}", x), Flow::Skip),
+        (0xF, 0, 0, 0) if xochip =>
+        {
+            let address = next?;
+            long_address = Some(address);
+            (format!("LD I, LONG {:#06X}", address), Flow::Next)
+        }
+        (0xF, n, 0x0, 0x1) if xochip => (format!("PLANE {}", n), Flow::Next),
+        (0xF, 0, 0x0, 0x2) if xochip => ("AUDIO".to_string(), Flow::Next),
+        (0xF, x, 0x0, 0x7) => (format!("LD V{:X}, DT", x), Flow::Next),
+        (0xF, x, 0x0, 0xA) => (format!("LD V{:X}, K", x), Flow::Next),
+        (0xF, x, 0x1, 0x5) => (format!("LD DT, V{:X}", x), Flow::Next),
+        (0xF, x, 0x1, 0x8) => (format!("LD ST, V{:X}", x), Flow::Next),
+        (0xF, x, 0x1, 0xE) => (format!("ADD I, V{:X}", x), Flow::Next),
+        (0xF, x, 0x2, 0x9) => (format!("LD F, V{:X}", x), Flow::Next),
+        (0xF, x, 0x3, 0x0) if schip => (format!("LD HF, V{:X}", x), Flow::Next),
+        (0xF, x, 0x3, 0x3) => (format!("LD B, V{:X}", x), Flow::Next),
+        (0xF, x, 0x3, 0xA) if xochip => (format!("PITCH V{:X}", x), Flow::Next),
+        (0xF, x, 0x5, 0x5) => (format!("LD [I], V{:X}", x), Flow::Next),
+        (0xF, x, 0x6, 0x5) => (format!("LD V{:X}, [I]", x), Flow::Next),
+        (0xF, x, 0x7, 0x5) if schip => (format!("LD R, V{:X}", x), Flow::Next),
+        (0xF, x, 0x8, 0x5) if schip => (format!("LD V{:X}, R", x), Flow::Next),
+        _ => return None,
+    };
+    let len = if long_address.is_some() { 4 } else { 2 };
+    Some(Instruction { opcode, len, long_address, mnemonic, flow })
+}
+
+fn word_at(rom: &[u8], offset: usize) -> Option<u16>
+{
+    if offset + 1 < rom.len()
+    {
+        Some(((rom[offset] as u16) << 8) | rom[offset + 1] as u16)
+    }
+    else
+    {
+        None
+    }
+}
+
+fn decode_at(rom: &[u8], offset: usize, variant: Variant) -> Option<Instruction>
+{
+    decode(word_at(rom, offset)?, word_at(rom, offset + 2), variant)
+}
+
+// Decodes every pair of bytes of the rom as an instruction, `origin` being the
+// address the rom is loaded at
+pub fn linear(rom: &[u8], origin: usize, variant: Variant) -> Vec<Line>
+{
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < rom.len()
+    {
+        let address = origin + offset;
+        match (decode_at(rom, offset, variant), word_at(rom, offset))
+        {
+            (Some(instruction), _) =>
+            {
+                offset += instruction.len;
+                lines.push(Line { address, kind: LineKind::Code(instruction) });
+            }
+            (None, Some(opcode)) =>
+            {
+                offset += 2;
+                lines.push(Line { address, kind: LineKind::Unknown(opcode) });
+            }
+            (None, None) =>
+            {
+                offset += 1;
+                lines.push(Line { address, kind: LineKind::Data(rom[address - origin]) });
+            }
+        }
+    }
+    lines
+}
+
+// Follows the code from the entry point through jumps, calls and skips, everything
+// that isn't reached is shown as data
+pub fn recursive(rom: &[u8], origin: usize, variant: Variant) -> Vec<Line>
+{
+    let mut code: BTreeSet<usize> = BTreeSet::new();
+    let mut pending = VecDeque::new();
+    pending.push_back(origin);
+    while let Some(address) = pending.pop_front()
+    {
+        if address < origin || code.contains(&address)
+        {
+            continue;
+        }
+        let instruction = match decode_at(rom, address - origin, variant)
+        {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+        code.insert(address);
+        let next = address + instruction.len;
+        match instruction.flow
+        {
+            Flow::Next => pending.push_back(next),
+            // The skipped instruction can be a 4 byte long load on XO-CHIP
+            Flow::Skip =>
+            {
+                pending.push_back(next);
+                let skipped_len = decode_at(rom, next - origin, variant).map_or(2, |i| i.len);
+                pending.push_back(next + skipped_len);
+            }
+            Flow::Jump(target) => pending.push_back(target),
+            Flow::Call(target) =>
+            {
+                pending.push_back(target);
+                pending.push_back(next);
+            }
+            Flow::Computed | Flow::Return | Flow::Stop => {}
+        }
+    }
+
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < rom.len()
+    {
+        let address = origin + offset;
+        match decode_at(rom, offset, variant)
+        {
+            Some(instruction) if code.contains(&address) =>
+            {
+                offset += instruction.len;
+                lines.push(Line { address, kind: LineKind::Code(instruction) });
+            }
+            _ =>
+            {
+                offset += 1;
+                lines.push(Line { address, kind: LineKind::Data(rom[address - origin]) });
+            }
+        }
+    }
+    lines
+}
+
+// Disassembles the instructions around an address of the memory, for debugging
+pub fn window(mem: &[u8], center: usize, before: usize, after: usize, variant: Variant) -> Vec<Line>
+{
+    let end = (center + after * 2 + 2).min(mem.len());
+    let start = center.saturating_sub(before * 2).min(end);
+    // One more word so a long load at the end is still decoded
+    let lines = linear(&mem[start..(end + 2).min(mem.len())], start, variant);
+    lines.into_iter().filter(|line| line.address < end).collect()
+}
+
+// One line of listing: address, raw bytes and mnemonic
+pub fn format_line(line: &Line) -> String
+{
+    match line.kind
+    {
+        LineKind::Code(ref instruction) => match instruction.long_address
+        {
+            Some(long_address) => format!("{:#06X}  {:04X}{:04X}  {}", line.address, instruction.opcode,
+                                            long_address, instruction.mnemonic),
+            None => format!("{:#06X}  {:04X}      {}", line.address, instruction.opcode, instruction.mnemonic),
+        },
+        LineKind::Unknown(opcode) =>
+            format!("{:#06X}  {:04X}      DW {:#06X}", line.address, opcode, opcode),
+        LineKind::Data(byte) =>
+        {
+            let pixels: String = (0..8).map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' }).collect();
+            format!("{:#06X}  {:02X}        DB {:#04X}  ; {}", line.address, byte, byte, pixels)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{linear, recursive, window, Line, LineKind};
+    use super::super::Variant;
+
+    fn addresses(lines: &[Line]) -> Vec<usize>
+    {
+        lines.iter().map(|line| line.address).collect()
+    }
+
+    fn is_code(line: &Line) -> bool
+    {
+        matches!(line.kind, LineKind::Code(_))
+    }
+
+    #[test]
+    fn sprites_after_a_jump_are_data()
+    {
+        // LD I, 0x206 ; DRW V0, V1, 4 ; JP 0x204 ; a sprite which decodes as SE and OR
+        let rom = [0xA2, 0x06, 0xD0, 0x14, 0x12, 0x04, 0x3C, 0x42, 0x81, 0x81];
+        let lines = recursive(&rom, 0x200, Variant::Chip8);
+        assert_eq!(addresses(&lines), vec![0x200, 0x202, 0x204, 0x206, 0x207, 0x208, 0x209]);
+        assert!(lines[..3].iter().all(is_code));
+        assert_eq!(lines[3].kind, LineKind::Data(0x3C));
+        assert!(lines[3..].iter().all(|line| !is_code(line)));
+        // Without following the code they are instructions
+        assert!(linear(&rom, 0x200, Variant::Chip8).iter().all(is_code));
+    }
+
+    #[test]
+    fn both_arms_of_a_skip_are_followed()
+    {
+        // SE V0, 0 ; JP 0x206 ; CLS ; JP 0x206
+        let rom = [0x30, 0x00, 0x12, 0x06, 0x00, 0xE0, 0x12, 0x06];
+        let lines = recursive(&rom, 0x200, Variant::Chip8);
+        assert_eq!(addresses(&lines), vec![0x200, 0x202, 0x204, 0x206]);
+        assert!(lines.iter().all(is_code));
+
+        // SE V0, 0 ; LD I, LONG 0x20A ; EXIT ; JP 0x200 (never reached)
+        let rom = [0x30, 0x00, 0xF0, 0x00, 0x02, 0x0A, 0x00, 0xFD, 0x12, 0x00];
+        let lines = recursive(&rom, 0x200, Variant::XoChip);
+        assert_eq!(addresses(&lines), vec![0x200, 0x202, 0x206, 0x208, 0x209]);
+        assert!(lines[..3].iter().all(is_code));
+        assert_eq!(lines[3].kind, LineKind::Data(0x12));
+    }
+
+    #[test]
+    fn window_at_the_edges_of_memory()
+    {
+        let mut mem = vec![0; 0x1000];
+        mem[0..2].copy_from_slice(&[0x00, 0xE0]);
+        assert_eq!(addresses(&window(&mem, 0, 2, 2, Variant::Chip8)), vec![0, 2, 4]);
+        assert!(is_code(&window(&mem, 0, 2, 2, Variant::Chip8)[0]));
+        assert_eq!(addresses(&window(&mem, 0xFFE, 2, 2, Variant::Chip8)), vec![0xFFA, 0xFFC, 0xFFE]);
+        // A long load needs the word after it, which isn't there at the very end
+        mem[0xFFC..].copy_from_slice(&[0xF0, 0x00, 0x12, 0x34]);
+        let lines = window(&mem, 0xFFC, 0, 1, Variant::XoChip);
+        assert_eq!(addresses(&lines), vec![0xFFC]);
+        match lines[0].kind
+        {
+            LineKind::Code(ref instruction) => assert_eq!(instruction.mnemonic, "LD I, LONG 0x1234"),
+            ref kind => panic!("{:?} isn't code", kind),
+        }
+        mem[0xFFE..].copy_from_slice(&[0xF0, 0x00]);
+        assert_eq!(window(&mem, 0xFFE, 0, 0, Variant::XoChip)[0].kind, LineKind::Unknown(0xF000));
+    }
+}
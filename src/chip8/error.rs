@@ -1,6 +1,23 @@
+use super::fault::Fault;
+
 error_chain!
 {
+    errors {
+        Fault(fault: Fault) {
+            description("fault of the machine")
+            display("{}", fault)
+        }
+    }
+
     foreign_links {
         Io(::std::io::Error) #[cfg(unix)];
     }
 }
+
+impl From<Fault> for Error
+{
+    fn from(fault: Fault) -> Error
+    {
+        ErrorKind::Fault(fault).into()
+    }
+}
@@ -0,0 +1,329 @@
+use std::fmt;
+use super::Result;
+
+// Ways the program can crash the machine
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultKind
+{
+    // CALL with the 15 levels of the stack in use
+    StackOverflow,
+    // RET outside of a subroutine
+    StackUnderflow,
+    InvalidOpcode,
+    // Access to the address, past the end of the memory
+    MemoryOutOfRange(usize),
+    // SKP or SKNP of a register holding the value, which isn't a key
+    InvalidKey(u8),
+    // Jump, call, return or skip to the address, leaving no room for an instruction
+    PcOutOfRange(usize),
+}
+
+impl fmt::Display for FaultKind
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            FaultKind::StackOverflow => write!(f, "Stack overflow"),
+            FaultKind::StackUnderflow => write!(f, "Stack underflow"),
+            FaultKind::InvalidOpcode => write!(f, "Invalid opcode"),
+            FaultKind::MemoryOutOfRange(address) => write!(f, "Memory address {:#06X} out of range", address),
+            FaultKind::InvalidKey(key) => write!(f, "Invalid key {:#04X}", key),
+            FaultKind::PcOutOfRange(address) => write!(f, "Program counter {:#06X} out of range", address),
+        }
+    }
+}
+
+// Crash of the machine, with the instruction that caused it. The instruction had no
+// effect, the machine is left as it was before it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fault
+{
+    pub kind: FaultKind,
+    pub pc: usize,
+    pub opcode: u16,
+}
+
+impl fmt::Display for Fault
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{} executing instruction {:#06X} at {:#06X}", self.kind, self.opcode, self.pc)
+    }
+}
+
+// What the runner does when the machine faults
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultPolicy
+{
+    // Stop the machine, keeping the last frame on the screen, and fail once the
+    // frontend is closed
+    Halt,
+    // Stop in the debugger, starting it if needed
+    Debug,
+    // Print the fault and go on with the next instruction
+    Skip,
+    // Wrap the address around the memory instead of faulting, only for memory and
+    // program counter faults
+    Wrap,
+}
+
+pub const POLICY_NAMES: [&str; 4] = ["halt", "debug", "skip", "wrap"];
+
+impl FaultPolicy
+{
+    pub fn from_name(name: &str) -> Option<FaultPolicy>
+    {
+        match name
+        {
+            "halt" => Some(FaultPolicy::Halt),
+            "debug" => Some(FaultPolicy::Debug),
+            "skip" => Some(FaultPolicy::Skip),
+            "wrap" => Some(FaultPolicy::Wrap),
+            _ => None,
+        }
+    }
+}
+
+// Policy of every kind of fault
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FaultPolicies
+{
+    pub stack_overflow: FaultPolicy,
+    pub stack_underflow: FaultPolicy,
+    pub invalid_opcode: FaultPolicy,
+    pub memory: FaultPolicy,
+    pub invalid_key: FaultPolicy,
+    pub pc: FaultPolicy,
+}
+
+impl FaultPolicies
+{
+    pub fn all(policy: FaultPolicy) -> FaultPolicies
+    {
+        FaultPolicies
+        {
+            stack_overflow: policy,
+            stack_underflow: policy,
+            invalid_opcode: policy,
+            memory: policy,
+            invalid_key: policy,
+            pc: policy,
+        }
+    }
+
+    pub fn policy(&self, kind: FaultKind) -> FaultPolicy
+    {
+        match kind
+        {
+            FaultKind::StackOverflow => self.stack_overflow,
+            FaultKind::StackUnderflow => self.stack_underflow,
+            FaultKind::InvalidOpcode => self.invalid_opcode,
+            FaultKind::MemoryOutOfRange(_) => self.memory,
+            FaultKind::InvalidKey(_) => self.invalid_key,
+            FaultKind::PcOutOfRange(_) => self.pc,
+        }
+    }
+
+    // Comma separated policies, for every fault or for a kind of fault:
+    // "skip", "debug,memory=wrap,opcode=skip". A bare wrap only applies to the faults
+    // that can wrap, memory and pc
+    pub fn parse(text: &str) -> Result<FaultPolicies>
+    {
+        let mut policies = FaultPolicies::default();
+        for item in text.split(',').map(str::trim).filter(|item| !item.is_empty())
+        {
+            let (kind, name) = match item.find('=')
+            {
+                Some(equal) => (Some(item[..equal].trim()), item[equal + 1..].trim()),
+                None => (None, item),
+            };
+            let policy = match FaultPolicy::from_name(name)
+            {
+                Some(policy) => policy,
+                None => bail!(format!("Unknown fault policy {}, expected one of: {}",
+                                        name, POLICY_NAMES.join(", "))),
+            };
+            match kind
+            {
+                None if policy == FaultPolicy::Wrap =>
+                {
+                    policies.memory = policy;
+                    policies.pc = policy;
+                }
+                None => policies = FaultPolicies::all(policy),
+                Some("stack") =>
+                {
+                    policies.stack_overflow = policy;
+                    policies.stack_underflow = policy;
+                }
+                Some("stack-overflow") => policies.stack_overflow = policy,
+                Some("stack-underflow") => policies.stack_underflow = policy,
+                Some("opcode") => policies.invalid_opcode = policy,
+                Some("memory") => policies.memory = policy,
+                Some("key") => policies.invalid_key = policy,
+                Some("pc") => policies.pc = policy,
+                Some(kind) => bail!(format!("Unknown fault {}, expected stack, stack-overflow, \
+                                             stack-underflow, opcode, memory, key or pc", kind)),
+            }
+        }
+        let wrapped = [policies.stack_overflow, policies.stack_underflow,
+                       policies.invalid_opcode, policies.invalid_key];
+        if wrapped.contains(&FaultPolicy::Wrap)
+        {
+            bail!("Only memory and pc faults can wrap");
+        }
+        Ok(policies)
+    }
+}
+
+impl Default for FaultPolicies
+{
+    fn default() -> FaultPolicies
+    {
+        FaultPolicies::all(FaultPolicy::Halt)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{Fault, FaultKind, FaultPolicies, FaultPolicy};
+    use super::super::{Chip8, Quirks, Variant};
+
+    // Steps until the machine faults, checking that the faulting instruction left the
+    // program counter, the stack pointer and I as they were
+    fn fault_of(chip: &mut Chip8) -> Fault
+    {
+        for _ in 0..100
+        {
+            let before = (chip.program_counter, chip.stack_pointer, chip.index);
+            if let Err(fault) = chip.step()
+            {
+                assert_eq!((chip.program_counter, chip.stack_pointer, chip.index), before);
+                assert_eq!(fault.pc, before.0);
+                return fault;
+            }
+        }
+        panic!("no fault");
+    }
+
+    fn chip8(rom: &[u8]) -> Chip8
+    {
+        Chip8::new(Some(rom), Variant::Chip8, Quirks::default()).unwrap()
+    }
+
+    #[test]
+    fn stack_overflow()
+    {
+        // LD I, 0x300 ; CALL 0x202
+        let mut chip = chip8(&[0xA3, 0x00, 0x22, 0x02]);
+        let fault = fault_of(&mut chip);
+        assert_eq!((fault.kind, fault.opcode), (FaultKind::StackOverflow, 0x2202));
+        assert_eq!((chip.stack_pointer, chip.index), (15, 0x300));
+    }
+
+    #[test]
+    fn stack_underflow()
+    {
+        let mut chip = chip8(&[0x00, 0xEE]);
+        let fault = fault_of(&mut chip);
+        assert_eq!((fault.kind, fault.pc, fault.opcode), (FaultKind::StackUnderflow, 0x200, 0x00EE));
+    }
+
+    #[test]
+    fn pc_out_of_memory()
+    {
+        // CALL 0xFFF, the stack is put back
+        let mut chip = chip8(&[0x2F, 0xFF]);
+        assert_eq!(fault_of(&mut chip).kind, FaultKind::PcOutOfRange(0xFFF));
+        // Running into the end of memory, the last instruction doesn't run
+        let mut chip = chip8(&[0x1F, 0xFC]);
+        chip.mem[0xFFC..].copy_from_slice(&[0x60, 0x01, 0x60, 0x02]);
+        let fault = fault_of(&mut chip);
+        assert_eq!((fault.kind, fault.pc), (FaultKind::PcOutOfRange(0x1000), 0xFFE));
+        assert_eq!(chip.v_registers[0], 1);
+        // LD I, LONG 0x1234 at the end of the XO-CHIP memory, I is put back
+        let mut chip = Chip8::new(None, Variant::XoChip, Quirks::default()).unwrap();
+        chip.mem[0xFFFC..].copy_from_slice(&[0xF0, 0x00, 0x12, 0x34]);
+        chip.program_counter = 0xFFFC;
+        let fault = fault_of(&mut chip);
+        assert_eq!((fault.kind, fault.opcode), (FaultKind::PcOutOfRange(0x10000), 0xF000));
+        assert_eq!(chip.index, 0);
+    }
+
+    #[test]
+    fn i_out_of_memory()
+    {
+        // LD I, 0xFFF ; LD [I], V1 with the increment of I
+        let rom = [0xAF, 0xFF, 0xF1, 0x55];
+        let quirks = Quirks { load_store_increment: true, ..Quirks::default() };
+        let mut chip = Chip8::new(Some(&rom), Variant::Chip8, quirks).unwrap();
+        chip.v_registers[0] = 0xAB;
+        let fault = fault_of(&mut chip);
+        assert_eq!((fault.kind, fault.pc), (FaultKind::MemoryOutOfRange(0x1000), 0x202));
+        assert_eq!((chip.index, chip.mem[0xFFF]), (0xFFF, 0));
+        // LD V0, [I] past the end, and a sprite read past it
+        let mut chip = chip8(&[0xAF, 0xFF, 0xF1, 0x65]);
+        assert_eq!(fault_of(&mut chip).kind, FaultKind::MemoryOutOfRange(0x1000));
+        let mut chip = chip8(&[0xAF, 0xFE, 0xD0, 0x05]);
+        assert_eq!(fault_of(&mut chip).kind, FaultKind::MemoryOutOfRange(0x1000));
+    }
+
+    #[test]
+    fn invalid_key()
+    {
+        // LD V3, 0x10 ; SKP V3
+        let mut chip = chip8(&[0x63, 0x10, 0xE3, 0x9E]);
+        let fault = fault_of(&mut chip);
+        assert_eq!((fault.kind, fault.pc, fault.opcode), (FaultKind::InvalidKey(0x10), 0x202, 0xE39E));
+        // LD V3, 0xFF ; SKNP V3
+        let mut chip = chip8(&[0x63, 0xFF, 0xE3, 0xA1]);
+        assert_eq!(fault_of(&mut chip).kind, FaultKind::InvalidKey(0xFF));
+    }
+
+    #[test]
+    fn invalid_opcode()
+    {
+        let mut chip = chip8(&[0x51, 0x21]);
+        let fault = fault_of(&mut chip);
+        assert_eq!((fault.kind, fault.pc, fault.opcode), (FaultKind::InvalidOpcode, 0x200, 0x5121));
+        assert_eq!(fault.to_string(), "Invalid opcode executing instruction 0x5121 at 0x0200");
+    }
+
+    #[test]
+    fn policy_grammar()
+    {
+        use super::FaultPolicy::{Debug, Halt, Skip, Wrap};
+        assert_eq!(FaultPolicies::parse("").unwrap(), FaultPolicies::all(Halt));
+        assert_eq!(FaultPolicies::parse("skip").unwrap(), FaultPolicies::all(Skip));
+        // Later items override earlier ones, spaces are ignored
+        let policies = FaultPolicies::parse(" debug , memory = wrap,opcode=skip,").unwrap();
+        assert_eq!(policies, FaultPolicies { memory: Wrap, invalid_opcode: Skip, ..FaultPolicies::all(Debug) });
+        let policies = FaultPolicies::parse("opcode=skip,halt").unwrap();
+        assert_eq!(policies, FaultPolicies::all(Halt));
+        // A bare wrap only changes memory and pc
+        let policies = FaultPolicies::parse("skip,wrap").unwrap();
+        assert_eq!(policies, FaultPolicies { memory: Wrap, pc: Wrap, ..FaultPolicies::all(Skip) });
+        let policies = FaultPolicies::parse("stack=skip,key=debug").unwrap();
+        assert_eq!(policies, FaultPolicies { stack_overflow: Skip, stack_underflow: Skip, invalid_key: Debug,
+                                             ..FaultPolicies::all(Halt) });
+        let policies = FaultPolicies::parse("stack-overflow=skip,stack-underflow=debug,pc=wrap").unwrap();
+        assert_eq!(policies, FaultPolicies { stack_overflow: Skip, stack_underflow: Debug, pc: Wrap,
+                                             ..FaultPolicies::all(Halt) });
+        assert_eq!(policies.policy(FaultKind::PcOutOfRange(0x1000)), Wrap);
+        assert_eq!(policies.policy(FaultKind::InvalidKey(0x10)), FaultPolicy::Halt);
+    }
+
+    #[test]
+    fn invalid_policies()
+    {
+        let error = |text: &str| FaultPolicies::parse(text).unwrap_err().to_string();
+        assert_eq!(error("ignore"), "Unknown fault policy ignore, expected one of: halt, debug, skip, wrap");
+        assert_eq!(error("memory="), "Unknown fault policy , expected one of: halt, debug, skip, wrap");
+        assert_eq!(error("screen=skip"), "Unknown fault screen, expected stack, stack-overflow, \
+                                         stack-underflow, opcode, memory, key or pc");
+        assert_eq!(error("opcode=wrap"), "Only memory and pc faults can wrap");
+        assert_eq!(error("stack=wrap"), "Only memory and pc faults can wrap");
+    }
+}
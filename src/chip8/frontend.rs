@@ -0,0 +1,151 @@
+use super::Result;
+pub use super::Screen;
+
+// Sink for the frames produced by the interpreter
+pub trait Display
+{
+    fn draw(&mut self, screen: &Screen) -> Result<()>;
+
+    // Called instead of draw on frames where the screen didn't change
+    fn refresh(&mut self) -> Result<()>
+    {
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool
+    {
+        true
+    }
+
+    // Shows the state of the emulator, like the speed
+    fn set_status(&mut self, _status: &str) {}
+}
+
+// Requests of the user to the emulator rather than to the program
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command
+{
+    SaveState(u8),
+    LoadState(u8),
+    // Sent on every frame the rewind hotkey is held
+    Rewind,
+    SpeedUp,
+    SlowDown,
+    TogglePause,
+    // Sent on every frame the hotkeys are held
+    FastForward,
+    SlowMotion,
+}
+
+// Source of the state of the 16 keys of the hex keypad
+pub trait Keypad
+{
+    fn is_key_down(&self, hexcode: u8) -> bool;
+
+    // Hotkeys pressed since the last call
+    fn commands(&mut self) -> Vec<Command>
+    {
+        Vec::new()
+    }
+}
+
+// Sink for the state of the buzzer, active while the sound timer is not 0
+pub trait Buzzer
+{
+    fn set_buzzing(&mut self, buzzing: bool);
+
+    // XO-CHIP sound played instead of the tone while buzzing, 128 1 bit samples
+    // looped at `rate` Hz. Called before set_buzzing on every frame
+    fn set_pattern(&mut self, _pattern: &[u8; 0x10], _rate: f32) {}
+}
+
+impl<B: Buzzer + ?Sized> Buzzer for Box<B>
+{
+    fn set_buzzing(&mut self, buzzing: bool)
+    {
+        (**self).set_buzzing(buzzing);
+    }
+
+    fn set_pattern(&mut self, pattern: &[u8; 0x10], rate: f32)
+    {
+        (**self).set_pattern(pattern, rate);
+    }
+}
+
+// Everything the interpreter needs to talk to the outside world
+pub trait Frontend: Display + Keypad + Buzzer {}
+impl<T: Display + Keypad + Buzzer> Frontend for T {}
+
+// Frontend made out of independent display, keypad and buzzer implementations
+pub struct Composite<D: Display, K: Keypad, B: Buzzer>
+{
+    pub display: D,
+    pub keypad: K,
+    pub buzzer: B,
+}
+
+impl<D: Display, K: Keypad, B: Buzzer> Display for Composite<D, K, B>
+{
+    fn draw(&mut self, screen: &Screen) -> Result<()>
+    {
+        self.display.draw(screen)
+    }
+
+    fn refresh(&mut self) -> Result<()>
+    {
+        self.display.refresh()
+    }
+
+    fn is_open(&self) -> bool
+    {
+        self.display.is_open()
+    }
+
+    fn set_status(&mut self, status: &str)
+    {
+        self.display.set_status(status)
+    }
+}
+
+impl<D: Display, K: Keypad, B: Buzzer> Keypad for Composite<D, K, B>
+{
+    fn is_key_down(&self, hexcode: u8) -> bool
+    {
+        self.keypad.is_key_down(hexcode)
+    }
+
+    fn commands(&mut self) -> Vec<Command>
+    {
+        self.keypad.commands()
+    }
+}
+
+impl<D: Display, K: Keypad, B: Buzzer> Buzzer for Composite<D, K, B>
+{
+    fn set_buzzing(&mut self, buzzing: bool)
+    {
+        self.buzzer.set_buzzing(buzzing);
+    }
+
+    fn set_pattern(&mut self, pattern: &[u8; 0x10], rate: f32)
+    {
+        self.buzzer.set_pattern(pattern, rate);
+    }
+}
+
+pub struct NullKeypad;
+
+impl Keypad for NullKeypad
+{
+    fn is_key_down(&self, _hexcode: u8) -> bool
+    {
+        false
+    }
+}
+
+pub struct NullBuzzer;
+
+impl Buzzer for NullBuzzer
+{
+    fn set_buzzing(&mut self, _buzzing: bool) {}
+}
@@ -0,0 +1,133 @@
+use std::path::Path;
+use super::{image, Result};
+use super::frontend::{Display, Screen};
+
+// Frames run when --frames isn't given, 10 seconds of the machine
+pub const DEFAULT_FRAMES: usize = 600;
+
+// Frames of a headless run: the ones asked for, or the whole movie played, or
+// DEFAULT_FRAMES
+pub fn frame_count(frames: Option<usize>, movie_frames: Option<usize>) -> usize
+{
+    frames.or(movie_frames).unwrap_or(DEFAULT_FRAMES)
+}
+
+// Display of the runs without a window: closes after a number of frames and can
+// save the screen to an image file every few frames
+pub struct HeadlessDisplay
+{
+    frames: usize,
+    // Frames shown so far
+    frame: usize,
+    screen: Screen,
+    dump_every: usize,
+    dump_path: String,
+}
+
+impl HeadlessDisplay
+{
+    pub fn new(frames: usize) -> HeadlessDisplay
+    {
+        HeadlessDisplay
+        {
+            frames,
+            frame: 0,
+            screen: Screen::new(),
+            dump_every: 0,
+            dump_path: String::new(),
+        }
+    }
+
+    // Saves every `every` frames to `path` numbered with the frame, out.png giving
+    // out_000060.png, out_000120.png...
+    pub fn dump_every(&mut self, every: usize, path: &str)
+    {
+        self.dump_every = every;
+        self.dump_path = path.to_string();
+    }
+
+    fn numbered_path(&self) -> String
+    {
+        let path = Path::new(&self.dump_path);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match path.extension()
+        {
+            Some(extension) => format!("{}_{:06}.{}", stem, self.frame, extension.to_string_lossy()),
+            None => format!("{}_{:06}", stem, self.frame),
+        };
+        path.with_file_name(name).to_string_lossy().into_owned()
+    }
+
+    fn next_frame(&mut self) -> Result<()>
+    {
+        self.frame += 1;
+        if self.dump_every > 0 && self.frame.is_multiple_of(self.dump_every)
+        {
+            image::save(&self.screen, &self.numbered_path())?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for HeadlessDisplay
+{
+    fn draw(&mut self, screen: &Screen) -> Result<()>
+    {
+        self.screen = screen.clone();
+        self.next_frame()
+    }
+
+    fn refresh(&mut self) -> Result<()>
+    {
+        self.next_frame()
+    }
+
+    fn is_open(&self) -> bool
+    {
+        self.frame < self.frames
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::fs;
+    use super::{frame_count, HeadlessDisplay};
+    use super::super::frontend::{Display, Screen};
+
+    #[test]
+    fn frames_default_to_the_movie_then_600()
+    {
+        assert_eq!(frame_count(None, None), 600);
+        assert_eq!(frame_count(None, Some(1234)), 1234);
+        assert_eq!(frame_count(Some(10), Some(1234)), 10);
+        assert_eq!(frame_count(Some(10), None), 10);
+    }
+
+    #[test]
+    fn closes_after_the_frames_and_dumps_every_few()
+    {
+        let directory = std::env::temp_dir().join(format!("chip8_dump_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("out.pbm").to_string_lossy().into_owned();
+        let mut display = HeadlessDisplay::new(5);
+        display.dump_every(2, &path);
+        let mut screen = Screen::new();
+        screen.toggle(0, 0, 1);
+        let mut frames = 0;
+        while display.is_open()
+        {
+            display.draw(&screen).unwrap();
+            frames += 1;
+        }
+        assert_eq!(frames, 5);
+        let mut names: Vec<String> = fs::read_dir(&directory).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        assert_eq!(names, ["out_000002.pbm", "out_000004.pbm"]);
+        let data = fs::read(directory.join("out_000002.pbm")).unwrap();
+        assert_eq!(&data[..9], b"P4\n64 32\n");
+        assert_eq!(data[9], 0x80);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+use std::fs;
+use std::path::Path;
+use super::{Result, ResultExt};
+use super::screen::{Screen, PALETTE};
+use super::state::crc32;
+
+// Formats the screen can be saved in, one pixel of the image per pixel of the screen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat
+{
+    // Black and white, every lit pixel is black
+    Pbm,
+    Ppm,
+    Png,
+}
+
+impl ImageFormat
+{
+    // Format given by the extension of the file name
+    pub fn from_path(path: &str) -> Option<ImageFormat>
+    {
+        let extension = Path::new(path).extension()?.to_string_lossy().to_lowercase();
+        match extension.as_str()
+        {
+            "pbm" => Some(ImageFormat::Pbm),
+            "ppm" => Some(ImageFormat::Ppm),
+            "png" => Some(ImageFormat::Png),
+            _ => None,
+        }
+    }
+}
+
+// Red, green and blue of every pixel, row by row
+fn rgb_rows(screen: &Screen) -> Vec<Vec<u8>>
+{
+    (0..screen.height()).map(|y|
+    {
+        (0..screen.width()).flat_map(|x|
+        {
+            let colour = PALETTE[screen.get(x, y) as usize];
+            vec![(colour >> 16) as u8, (colour >> 8) as u8, colour as u8]
+        }).collect()
+    }).collect()
+}
+
+// Binary PBM, 8 pixels per byte
+fn encode_pbm(screen: &Screen) -> Vec<u8>
+{
+    let mut data = format!("P4\n{} {}\n", screen.width(), screen.height()).into_bytes();
+    for y in 0..screen.height()
+    {
+        for x in (0..screen.width()).step_by(8)
+        {
+            let mut byte = 0u8;
+            for bit in 0..8
+            {
+                if screen.get(x + bit, y) != 0
+                {
+                    byte |= 0x80 >> bit;
+                }
+            }
+            data.push(byte);
+        }
+    }
+    data
+}
+
+// Binary PPM
+fn encode_ppm(screen: &Screen) -> Vec<u8>
+{
+    let mut data = format!("P6\n{} {}\n255\n", screen.width(), screen.height()).into_bytes();
+    for row in rgb_rows(screen)
+    {
+        data.extend(row);
+    }
+    data
+}
+
+fn adler32(data: &[u8]) -> u32
+{
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data
+    {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+// zlib stream made of uncompressed deflate blocks, the images are tiny
+fn zlib_stored(data: &[u8]) -> Vec<u8>
+{
+    let mut out = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = data.chunks(0xFFFF).collect();
+    for (i, block) in blocks.iter().enumerate()
+    {
+        let last = i + 1 == blocks.len();
+        out.push(last as u8);
+        out.extend(&(block.len() as u16).to_le_bytes());
+        out.extend(&(!(block.len() as u16)).to_le_bytes());
+        out.extend(*block);
+    }
+    out.extend(&adler32(data).to_be_bytes());
+    out
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8])
+{
+    out.extend(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend(kind);
+    out.extend(data);
+    let crc = crc32(&out[start..]);
+    out.extend(&crc.to_be_bytes());
+}
+
+// 8 bit RGB PNG
+fn encode_png(screen: &Screen) -> Vec<u8>
+{
+    let mut header = Vec::new();
+    header.extend(&(screen.width() as u32).to_be_bytes());
+    header.extend(&(screen.height() as u32).to_be_bytes());
+    // Bit depth, colour type (RGB), compression, filter and interlace methods
+    header.extend(&[8, 2, 0, 0, 0]);
+    let mut pixels = Vec::new();
+    for row in rgb_rows(screen)
+    {
+        // No filter
+        pixels.push(0);
+        pixels.extend(row);
+    }
+
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    png_chunk(&mut out, b"IHDR", &header);
+    png_chunk(&mut out, b"IDAT", &zlib_stored(&pixels));
+    png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+pub fn encode(screen: &Screen, format: ImageFormat) -> Vec<u8>
+{
+    match format
+    {
+        ImageFormat::Pbm => encode_pbm(screen),
+        ImageFormat::Ppm => encode_ppm(screen),
+        ImageFormat::Png => encode_png(screen),
+    }
+}
+
+// Saves the screen in the format given by the extension of `path`
+pub fn save(screen: &Screen, path: &str) -> Result<()>
+{
+    let format = match ImageFormat::from_path(path)
+    {
+        Some(format) => format,
+        None => bail!(format!("Unknown image format of {}, expected png, pbm or ppm", path)),
+    };
+    fs::write(path, encode(screen, format)).chain_err(|| format!("Couldn't write {}", path))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{adler32, encode, ImageFormat};
+    use super::super::screen::Screen;
+
+    // Top left pixel in the first plane (white), bottom right one in the second (grey)
+    fn corners() -> Screen
+    {
+        let mut screen = Screen::new();
+        screen.toggle(0, 0, 1);
+        screen.toggle(63, 31, 2);
+        screen
+    }
+
+    #[test]
+    fn formats_from_the_extension()
+    {
+        assert_eq!(ImageFormat::from_path("a/out.PNG"), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::from_path("out.pbm"), Some(ImageFormat::Pbm));
+        assert_eq!(ImageFormat::from_path("out.ppm"), Some(ImageFormat::Ppm));
+        assert_eq!(ImageFormat::from_path("out.bmp"), None);
+        assert_eq!(ImageFormat::from_path("out"), None);
+    }
+
+    #[test]
+    fn pbm()
+    {
+        let mut expected = b"P4\n64 32\n".to_vec();
+        let mut pixels = vec![0u8; 8 * 32];
+        pixels[0] = 0x80;
+        pixels[8 * 32 - 1] = 0x01;
+        expected.extend(pixels);
+        assert_eq!(encode(&corners(), ImageFormat::Pbm), expected);
+    }
+
+    #[test]
+    fn ppm()
+    {
+        let mut expected = b"P6\n64 32\n255\n".to_vec();
+        let mut pixels = vec![0u8; 64 * 32 * 3];
+        pixels[..3].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+        pixels[64 * 32 * 3 - 3..].copy_from_slice(&[0x80, 0x80, 0x80]);
+        expected.extend(pixels);
+        assert_eq!(encode(&corners(), ImageFormat::Ppm), expected);
+    }
+
+    #[test]
+    fn adler()
+    {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn png()
+    {
+        let data = encode(&corners(), ImageFormat::Png);
+        // 32 rows of a filter byte and 64 RGB pixels
+        let raw_len = 32 * (1 + 64 * 3);
+        let mut pixels = vec![0u8; raw_len];
+        pixels[1..4].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+        pixels[raw_len - 3..].copy_from_slice(&[0x80, 0x80, 0x80]);
+
+        let mut expected = b"\x89PNG\r\n\x1a\n".to_vec();
+        expected.extend(&[0, 0, 0, 13]);
+        expected.extend(b"IHDR");
+        expected.extend(&[0, 0, 0, 64, 0, 0, 0, 32, 8, 2, 0, 0, 0]);
+        expected.extend(&[0x2D, 0xFF, 0xE9, 0xD3]);
+        // zlib header, one last stored block and the Adler-32 of the pixels
+        expected.extend(&[0x00, 0x00, 0x18, 0x2B]);
+        expected.extend(b"IDAT");
+        expected.extend(&[0x78, 0x01, 0x01, 0x20, 0x18, 0xDF, 0xE7]);
+        expected.extend(pixels);
+        expected.extend(&[0x30, 0xFE, 0x04, 0x7E]);
+        expected.extend(&[0x15, 0x03, 0x3C, 0xE6]);
+        expected.extend(&[0, 0, 0, 0]);
+        expected.extend(b"IEND");
+        expected.extend(&[0xAE, 0x42, 0x60, 0x82]);
+        assert_eq!(data, expected);
+    }
+}
@@ -0,0 +1,165 @@
+use std::fs;
+use super::{Result, ResultExt};
+use super::frontend::Keypad;
+
+// Key presses read from a file instead of the keyboard, to replay the same session.
+// Every line holds a frame number followed by keys going down (+) or up (-) when
+// that frame starts, frames counting from 0:
+//  # walk right for a second and shoot
+//  120 +6
+//  180 -6 +5
+//  184 -5
+pub struct InputScript
+{
+    // Frame, key and whether it goes down, sorted by frame
+    events: Vec<(u64, u8, bool)>,
+    next: usize,
+    keys: [bool; 16],
+}
+
+impl InputScript
+{
+    pub fn parse(text: &str) -> Result<InputScript>
+    {
+        let mut events = Vec::new();
+        for (number, line) in text.lines().enumerate()
+        {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            let frame = match words.next()
+            {
+                Some(frame) => frame,
+                None => continue,
+            };
+            let frame: u64 = frame.parse()
+                                .map_err(|_| format!("Line {}: invalid frame {}", number + 1, frame))?;
+            let mut has_keys = false;
+            for word in words
+            {
+                let pressed = match word.chars().next()
+                {
+                    Some('+') => true,
+                    Some('-') => false,
+                    _ => bail!(format!("Line {}: expected +KEY or -KEY, found {}", number + 1, word)),
+                };
+                let key = match u8::from_str_radix(&word[1..], 16)
+                {
+                    Ok(key) if key < 0x10 => key,
+                    _ => bail!(format!("Line {}: invalid key {}, expected 0-F", number + 1, &word[1..])),
+                };
+                events.push((frame, key, pressed));
+                has_keys = true;
+            }
+            if !has_keys
+            {
+                bail!(format!("Line {}: no keys for frame {}", number + 1, frame));
+            }
+        }
+        // Stable, the events of a frame keep the order of the file
+        events.sort_by_key(|event| event.0);
+        Ok(InputScript { events, next: 0, keys: [false; 16] })
+    }
+
+    pub fn load(path: &str) -> Result<InputScript>
+    {
+        let text = fs::read_to_string(path).chain_err(|| format!("Couldn't read {}", path))?;
+        InputScript::parse(&text).chain_err(|| format!("Error in input file {}", path))
+    }
+
+    // Applies the events up to `frame`, from the start again when the machine went
+    // back before the last one applied
+    pub fn advance(&mut self, frame: u64)
+    {
+        if self.next > 0 && self.events[self.next - 1].0 > frame
+        {
+            self.next = 0;
+            self.keys = [false; 16];
+        }
+        while let Some(&(event_frame, key, pressed)) = self.events.get(self.next)
+        {
+            if event_frame > frame
+            {
+                break;
+            }
+            self.keys[key as usize] = pressed;
+            self.next += 1;
+        }
+    }
+}
+
+impl Keypad for InputScript
+{
+    fn is_key_down(&self, hexcode: u8) -> bool
+    {
+        self.keys[(hexcode & 0xF) as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::InputScript;
+    use super::super::frontend::Keypad;
+
+    fn held(script: &InputScript) -> u16
+    {
+        (0..16).filter(|key| script.is_key_down(*key)).fold(0, |keys, key| keys | 1 << key)
+    }
+
+    // Keys held during each of the first `frames` frames
+    fn keys_by_frame(script: &mut InputScript, frames: u64) -> Vec<u16>
+    {
+        (0..frames).map(|frame|
+        {
+            script.advance(frame);
+            held(script)
+        }).collect()
+    }
+
+    #[test]
+    fn presses_and_releases()
+    {
+        let mut script = InputScript::parse("1 +5 +a\n3 -5\n4 -A +F").unwrap();
+        assert_eq!(keys_by_frame(&mut script, 5), [0, 0x0420, 0x0420, 0x0400, 0x8000]);
+    }
+
+    #[test]
+    fn frames_out_of_order_are_sorted()
+    {
+        let mut script = InputScript::parse("4 -1\n0 +1\n2 +2 -1\n2 +1").unwrap();
+        assert_eq!(keys_by_frame(&mut script, 5), [0x2, 0x2, 0x6, 0x6, 0x4]);
+    }
+
+    #[test]
+    fn comments_and_blank_lines()
+    {
+        let text = "# start the game\n\n  0 +5 # shoot\n\t\n1 -5\n";
+        let mut script = InputScript::parse(text).unwrap();
+        assert_eq!(keys_by_frame(&mut script, 2), [0x20, 0]);
+    }
+
+    #[test]
+    fn invalid_lines()
+    {
+        let error = |text: &str| InputScript::parse(text).err().unwrap().to_string();
+        assert_eq!(error("0 +1\n1 +G"), "Line 2: invalid key G, expected 0-F");
+        assert_eq!(error("0 +10"), "Line 1: invalid key 10, expected 0-F");
+        assert_eq!(error("0 5"), "Line 1: expected +KEY or -KEY, found 5");
+        assert_eq!(error("x +5"), "Line 1: invalid frame x");
+        assert_eq!(error("-1 +5"), "Line 1: invalid frame -1");
+        assert_eq!(error("3 # nothing"), "Line 1: no keys for frame 3");
+    }
+
+    #[test]
+    fn going_back_in_time()
+    {
+        let mut script = InputScript::parse("1 +1\n3 +2\n5 -1").unwrap();
+        let keys = keys_by_frame(&mut script, 7);
+        // Back to frame 2 after frame 6, forward to 5, then back before the event of 5
+        for &frame in [2, 5, 3, 0].iter()
+        {
+            script.advance(frame);
+            assert_eq!(held(&script), keys[frame as usize], "frame {}", frame);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use minifb::Key;
+use super::{Result, ResultExt};
+use super::state::crc32;
+use super::window::HOTKEYS;
+
+// QWERTY block used when there's no configuration:
+//  1 2 3 C    1 2 3 4
+//  4 5 6 D    Q W E R
+//  7 8 9 E    A S D F
+//  A 0 B F    Z X C V
+const DEFAULT_KEYS: [(u8, Key); 16] =
+                        [(0x1, Key::Key1), (0x2, Key::Key2), (0x3, Key::Key3), (0xC, Key::Key4),
+                        (0x4, Key::Q), (0x5, Key::W), (0x6, Key::E), (0xD, Key::R),
+                        (0x7, Key::A), (0x8, Key::S), (0x9, Key::D), (0xE, Key::F),
+                        (0xA, Key::Z), (0x0, Key::X), (0xB, Key::C), (0xF, Key::V),];
+
+fn key_from_name(name: &str) -> Option<Key>
+{
+    let key = match name.to_lowercase().as_str()
+    {
+        "0" => Key::Key0, "1" => Key::Key1, "2" => Key::Key2, "3" => Key::Key3, "4" => Key::Key4,
+        "5" => Key::Key5, "6" => Key::Key6, "7" => Key::Key7, "8" => Key::Key8, "9" => Key::Key9,
+        "a" => Key::A, "b" => Key::B, "c" => Key::C, "d" => Key::D, "e" => Key::E, "f" => Key::F,
+        "g" => Key::G, "h" => Key::H, "i" => Key::I, "j" => Key::J, "k" => Key::K, "l" => Key::L,
+        "m" => Key::M, "n" => Key::N, "o" => Key::O, "p" => Key::P, "q" => Key::Q, "r" => Key::R,
+        "s" => Key::S, "t" => Key::T, "u" => Key::U, "v" => Key::V, "w" => Key::W, "x" => Key::X,
+        "y" => Key::Y, "z" => Key::Z,
+        "f1" => Key::F1, "f2" => Key::F2, "f3" => Key::F3, "f4" => Key::F4, "f5" => Key::F5,
+        "f6" => Key::F6, "f7" => Key::F7, "f8" => Key::F8, "f9" => Key::F9, "f10" => Key::F10,
+        "f11" => Key::F11, "f12" => Key::F12, "f13" => Key::F13, "f14" => Key::F14, "f15" => Key::F15,
+        "up" => Key::Up, "down" => Key::Down, "left" => Key::Left, "right" => Key::Right,
+        "apostrophe" => Key::Apostrophe, "backquote" => Key::Backquote, "backslash" => Key::Backslash,
+        "comma" => Key::Comma, "equal" => Key::Equal, "leftbracket" => Key::LeftBracket,
+        "minus" => Key::Minus, "period" => Key::Period, "rightbracket" => Key::RightBracket,
+        "semicolon" => Key::Semicolon, "slash" => Key::Slash,
+        "backspace" => Key::Backspace, "delete" => Key::Delete, "end" => Key::End, "enter" => Key::Enter,
+        "escape" => Key::Escape, "home" => Key::Home, "insert" => Key::Insert, "menu" => Key::Menu,
+        "pagedown" => Key::PageDown, "pageup" => Key::PageUp, "pause" => Key::Pause,
+        "space" => Key::Space, "tab" => Key::Tab,
+        "numlock" => Key::NumLock, "capslock" => Key::CapsLock, "scrolllock" => Key::ScrollLock,
+        "leftshift" => Key::LeftShift, "rightshift" => Key::RightShift,
+        "leftctrl" => Key::LeftCtrl, "rightctrl" => Key::RightCtrl,
+        "leftalt" => Key::LeftAlt, "rightalt" => Key::RightAlt,
+        "leftsuper" => Key::LeftSuper, "rightsuper" => Key::RightSuper,
+        "numpad0" => Key::NumPad0, "numpad1" => Key::NumPad1, "numpad2" => Key::NumPad2,
+        "numpad3" => Key::NumPad3, "numpad4" => Key::NumPad4, "numpad5" => Key::NumPad5,
+        "numpad6" => Key::NumPad6, "numpad7" => Key::NumPad7, "numpad8" => Key::NumPad8,
+        "numpad9" => Key::NumPad9, "numpaddot" => Key::NumPadDot, "numpadslash" => Key::NumPadSlash,
+        "numpadasterisk" => Key::NumPadAsterisk, "numpadminus" => Key::NumPadMinus,
+        "numpadplus" => Key::NumPadPlus, "numpadenter" => Key::NumPadEnter,
+        _ => return None,
+    };
+    Some(key)
+}
+
+// Host keys pressing each key of the hex keypad
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap
+{
+    keys: Vec<Vec<Key>>,
+}
+
+impl Keymap
+{
+    pub fn host_keys(&self, hexcode: u8) -> &[Key]
+    {
+        &self.keys[(hexcode & 0xF) as usize]
+    }
+}
+
+impl Default for Keymap
+{
+    fn default() -> Keymap
+    {
+        let mut keys = vec![Vec::new(); 0x10];
+        for (hexcode, key) in DEFAULT_KEYS.iter()
+        {
+            keys[*hexcode as usize].push(*key);
+        }
+        Keymap { keys }
+    }
+}
+
+// Roms a part of the configuration applies to
+#[derive(Clone, Debug, PartialEq)]
+enum Section
+{
+    Global,
+    // File name of the rom
+    Rom(String),
+    // crc32 of the rom
+    Hash(u32),
+}
+
+// Key bindings read from a file like:
+//  # Global bindings, several host keys can press the same key
+//  [global]
+//  4 = Q Left
+//  6 = E Right
+//  # Overrides for a rom, by file name
+//  [rom BRIX]
+//  5 = Space
+//  # or by crc32 of its contents
+//  [hash 1A2B3C4D]
+//  5 = Up
+// Keys that aren't bound keep the default QWERTY block, lines before the first
+// section are global. A host key presses a single key of the keypad, binding it
+// removes it from the key it was bound to before, and the hotkeys of the emulator
+// can't be bound
+pub struct KeymapConfig
+{
+    bindings: Vec<(Section, u8, Vec<Key>)>,
+}
+
+impl KeymapConfig
+{
+    pub fn parse(text: &str) -> Result<KeymapConfig>
+    {
+        let mut bindings = Vec::new();
+        let mut section = Section::Global;
+        for (number, line) in text.lines().enumerate()
+        {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty()
+            {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']')
+            {
+                let header = line[1..line.len() - 1].trim();
+                let (kind, name) = header.split_at(header.find(' ').unwrap_or(header.len()));
+                let name = name.trim();
+                section = match kind
+                {
+                    "global" if name.is_empty() => Section::Global,
+                    "rom" if !name.is_empty() => Section::Rom(name.to_string()),
+                    "hash" => match u32::from_str_radix(name, 16)
+                    {
+                        Ok(hash) => Section::Hash(hash),
+                        Err(_) => bail!(format!("Line {}: invalid hash {}", number + 1, name)),
+                    },
+                    _ => bail!(format!("Line {}: unknown section [{}], expected [global], [rom NAME] or [hash CRC32]",
+                                        number + 1, header)),
+                };
+                continue;
+            }
+            let (hexcode, host_keys) = match line.find('=')
+            {
+                Some(equal) => (line[..equal].trim(), line[equal + 1..].trim()),
+                None => bail!(format!("Line {}: expected KEY = HOST_KEYS", number + 1)),
+            };
+            let hexcode = match u8::from_str_radix(hexcode, 16)
+            {
+                Ok(hexcode) if hexcode < 0x10 => hexcode,
+                _ => bail!(format!("Line {}: invalid key {}, expected 0-F", number + 1, hexcode)),
+            };
+            let mut keys = Vec::new();
+            for name in host_keys.split_whitespace()
+            {
+                match key_from_name(name)
+                {
+                    Some(key) if HOTKEYS.contains(&key) =>
+                        bail!(format!("Line {}: {} is a hotkey of the emulator, it can't be bound",
+                                        number + 1, name)),
+                    Some(key) => keys.push(key),
+                    None => bail!(format!("Line {}: unknown host key {}", number + 1, name)),
+                }
+            }
+            bindings.push((section.clone(), hexcode, keys));
+        }
+        Ok(KeymapConfig { bindings })
+    }
+
+    pub fn load(path: &Path) -> Result<KeymapConfig>
+    {
+        let text = fs::read_to_string(path).chain_err(|| format!("Couldn't read {}", path.display()))?;
+        KeymapConfig::parse(&text).chain_err(|| format!("Error in keymap {}", path.display()))
+    }
+
+    // Bindings of a rom: the global ones, replaced by the ones of the sections of the rom
+    pub fn keymap(&self, rom_path: &str, rom: &[u8]) -> Keymap
+    {
+        let file_name = Path::new(rom_path).file_name().map(|name| name.to_string_lossy().into_owned());
+        let hash = crc32(rom);
+        let mut keymap = Keymap::default();
+        for global in [true, false].iter()
+        {
+            for (section, hexcode, keys) in self.bindings.iter()
+            {
+                let applies = match section
+                {
+                    Section::Global => *global,
+                    Section::Rom(name) => !*global && file_name.as_ref() == Some(name),
+                    Section::Hash(section_hash) => !*global && *section_hash == hash,
+                };
+                if applies
+                {
+                    for bound in keymap.keys.iter_mut()
+                    {
+                        bound.retain(|key| !keys.contains(key));
+                    }
+                    keymap.keys[*hexcode as usize] = keys.clone();
+                }
+            }
+        }
+        keymap
+    }
+}
+
+// $XDG_CONFIG_HOME/chip8/keymap.cfg, or ~/.config/chip8/keymap.cfg
+pub fn default_config_path() -> Option<PathBuf>
+{
+    let config = match env::var_os("XDG_CONFIG_HOME")
+    {
+        Some(config) => PathBuf::from(config),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config.join("chip8").join("keymap.cfg"))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use minifb::Key;
+    use super::{Keymap, KeymapConfig};
+
+    #[test]
+    fn rebound_host_key_leaves_its_default_key()
+    {
+        let config = KeymapConfig::parse("[global]\n5 = Q Space\n[rom BRIX]\n6 = Space").unwrap();
+        let keymap = config.keymap("roms/BRIX", &[]);
+        // Q pressed 4 by default
+        assert_eq!(keymap.host_keys(0x4), &[] as &[Key]);
+        assert_eq!(keymap.host_keys(0x5), &[Key::Q]);
+        assert_eq!(keymap.host_keys(0x6), &[Key::Space]);
+        let other = config.keymap("roms/PONG", &[]);
+        assert_eq!(other.host_keys(0x5), &[Key::Q, Key::Space]);
+        assert_eq!(other.host_keys(0x6), Keymap::default().host_keys(0x6));
+    }
+
+    #[test]
+    fn hotkeys_can_not_be_bound()
+    {
+        for line in ["1 = P", "2 = tab", "3 = F1", "4 = minus", "5 = backquote"].iter()
+        {
+            assert!(KeymapConfig::parse(line).is_err(), "{}", line);
+        }
+    }
+}
@@ -1,85 +1,170 @@
-use minifb::{Window, WindowOptions, Key, KeyRepeat};
-use std::{time::{Duration, Instant}};
-const C8_SCREEN_WIDTH: usize = 64;
-const C8_SCREEN_HEIGTH: usize = 32;
-const SCALE: minifb::Scale = minifb::Scale::X16;
+pub const C8_SCREEN_WIDTH: usize = 64;
+pub const C8_SCREEN_HEIGTH: usize = 32;
 
 mod error;
 pub use error::*;
 
+pub mod asm;
+pub mod audio;
+pub mod crash;
+pub mod debugger;
+pub mod disasm;
+pub mod fault;
+pub mod frontend;
+pub mod headless;
+pub mod image;
+pub mod input;
+pub mod keymap;
+pub mod movie;
+pub mod octo;
+pub mod quirks;
+pub mod rewind;
+pub mod rng;
+pub mod runner;
+pub mod scheduler;
+pub mod screen;
+pub mod state;
+pub mod trace;
+pub mod watchpoint;
+pub mod window;
 
+use std::collections::VecDeque;
+use fault::{Fault, FaultKind};
+use frontend::Keypad;
+pub use quirks::Quirks;
+pub use rng::{Rng, RngKind};
+pub use screen::Screen;
 
-const KEY_CODES: [(u8, Key);16] =
-                        [(0x1, Key::Key1), (0x2, Key::Key2), (0x3, Key::Key3), (0xC, Key::Key4),
-                        (0x4, Key::Q), (0x5, Key::W), (0x6, Key::E), (0xD, Key::R),
-                        (0x7, Key::A), (0x8, Key::S), (0x9, Key::D), (0xE, Key::F),
-                        (0xA, Key::Z), (0x0, Key::X), (0xB, Key::C), (0xF, Key::V),];
+// Address of the 10 byte SUPER-CHIP digits, right after the 5 byte ones
+const BIG_FONT_ADDR: usize = 0x50;
 
-fn get_hexcode_from_key(key: Key) -> Option<u8>
+// Address where programs are loaded and start executing
+pub const PROGRAM_START: usize = 0x200;
+
+// XO-CHIP sound until a program loads its own, a 250Hz square wave at the default pitch
+const DEFAULT_AUDIO_PATTERN: [u8; 0x10] = [0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00,
+                                           0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00];
+
+// Instructions remembered for the crash reports
+const HISTORY_LEN: usize = 32;
+
+// Instruction set understood by the interpreter, each one is a superset of the
+// previous one
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Variant
 {
-    for (h, k) in KEY_CODES.iter()
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl Variant
+{
+    pub fn from_name(name: &str) -> Option<Variant>
     {
-        if key == *k
+        match name.to_lowercase().as_str()
         {
-            return Some(*h);
+            "chip8" | "chip-8" => Some(Variant::Chip8),
+            "schip" | "superchip" | "super-chip" => Some(Variant::SuperChip),
+            "xochip" | "xo-chip" => Some(Variant::XoChip),
+            _ => None,
         }
     }
-    None
-}
-fn get_key_from_hexcode(hexcode: u8) -> Option<Key>
-{
-    for (h, k) in KEY_CODES.iter()
+
+    // Quirks the programs written for the variant usually expect
+    pub fn default_quirks(self) -> Quirks
+    {
+        match self
+        {
+            Variant::Chip8 => Quirks::default(),
+            Variant::SuperChip => Quirks::superchip(),
+            Variant::XoChip => Quirks::xochip(),
+        }
+    }
+
+    // XO-CHIP programs can use the whole 16 bit address space
+    pub fn memory_size(self) -> usize
     {
-        if hexcode == *h
+        match self
         {
-            return Some(*k);
+            Variant::XoChip => 0x10000,
+            _ => 0x1000,
         }
     }
-    None
 }
 
+// Access to memory made by an instruction, not counting its fetch
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemAccess
+{
+    pub address: usize,
+    pub write: bool,
+    pub value: u8,
+}
+
+#[derive(Clone)]
 pub struct Chip8
 {
-    mem: [u8; 0x1000], // 4096 memory size ; 8bits
+    mem: Vec<u8>, // 4096 memory size (64KiB on XO-CHIP) ; 8bits
     v_registers: [u8; 0x10], // 16 8bit v_registers
     program_counter: usize,
     stack_pointer: usize,
     stack: [usize; 0x10],
     index: usize,
-    screen_memory: [[bool; C8_SCREEN_WIDTH]; C8_SCREEN_HEIGTH], // 64*32 screen
+    screen_memory: Screen, // 64*32 screen, 128*64 in SUPER-CHIP high resolution
     dt: u8,
     st: u8,
-    window: Window,
+    keys: [bool; 0x10], // state of the 16 keys of the hex keypad
     waiting_for_key: Option<u8>,
+    waiting_for_vblank: bool,
     draw: bool,
+    exited: bool,
+    rpl_flags: [u8; 0x10], // SUPER-CHIP user flags, saved by Fx75 and restored by Fx85
+    audio_pattern: [u8; 0x10], // XO-CHIP 1 bit 128 samples sound, loaded by F002
+    pitch: u8, // XO-CHIP playback rate of the audio pattern, set by Fx3A
+    rng: Rng, // numbers of Cxkk
+    frame: u64, // frames run so far, counted by tick_timers
+    variant: Variant,
+    quirks: Quirks,
+    track_memory: bool, // record the memory accesses of every instruction for watchpoints
+    wrap_memory: bool, // addresses past the end of memory wrap around instead of faulting
+    wrap_pc: bool, // same for the program counter
+    mem_accesses: Vec<MemAccess>, // accesses of the last instruction when track_memory is set
+    history: VecDeque<(usize, u16)>, // address and opcode of the last instructions executed
 }
 
 impl Chip8
 {
-    pub fn new(rom :Option<[u8; 0x1000 - 0x200]>) -> Result<Chip8>
+    pub fn new(rom :Option<&[u8]>, variant: Variant, quirks: Quirks) -> Result<Chip8>
     {
         let mut new_chip = Chip8
         {
-            mem: [0; 0x1000],
+            mem: vec![0; variant.memory_size()],
             v_registers: [0; 0x10],
             program_counter: 0x200,
             stack_pointer: 0x0,
             stack: [0; 0x10],
             index: 0,
-            screen_memory: [[false; C8_SCREEN_WIDTH]; C8_SCREEN_HEIGTH],
+            screen_memory: Screen::new(),
             dt: 0,
             st: 0,
-            window: Window::new("Chip-8 Emulator by Satore",
-                                C8_SCREEN_WIDTH, C8_SCREEN_HEIGTH,
-                                    WindowOptions
-                                    {
-                                        borderless: false,
-                                        title: true,
-                                        resize: false,
-                                        scale: SCALE
-                                    }).chain_err(|| "Couldn't create window")?,
+            keys: [false; 0x10],
             waiting_for_key: None,
+            waiting_for_vblank: false,
             draw: false,
+            exited: false,
+            rpl_flags: [0; 0x10],
+            audio_pattern: DEFAULT_AUDIO_PATTERN,
+            pitch: 64,
+            rng: Rng::new(RngKind::Xorshift, rand::random()),
+            frame: 0,
+            variant,
+            quirks,
+            track_memory: false,
+            wrap_memory: false,
+            wrap_pc: false,
+            mem_accesses: Vec::new(),
+            history: VecDeque::with_capacity(HISTORY_LEN),
         };
         let hex_digits = [0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
                                 0x20, 0x60, 0x20, 0x20, 0x70, // 1
@@ -98,115 +183,283 @@ impl Chip8
                                 0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
                                 0xF0, 0x80, 0xF0, 0x80, 0x80, // F
                                 ];
-        (&mut new_chip.mem[0..hex_digits.len()]).copy_from_slice(&hex_digits);
+        let big_hex_digits = [0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+                                0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+                                0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+                                0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+                                0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+                                0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+                                0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+                                0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+                                0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+                                0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+                                0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+                                0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+                                0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+                                0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+                                0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+                                0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+                                ];
+        new_chip.mem[0..hex_digits.len()].copy_from_slice(&hex_digits);
+        new_chip.mem[BIG_FONT_ADDR..BIG_FONT_ADDR + big_hex_digits.len()].copy_from_slice(&big_hex_digits);
         if let Some(urom) = rom
         {
-            (&mut new_chip.mem[0x200..]).copy_from_slice(&urom);
+            let max_size = new_chip.mem.len() - PROGRAM_START;
+            if urom.len() > max_size
+            {
+                bail!(format!("Rom can't be bigger than {}B and it is {}B", max_size, urom.len()));
+            }
+            new_chip.mem[PROGRAM_START..PROGRAM_START + urom.len()].copy_from_slice(urom);
         }
         Ok(new_chip)
     }
 
-    #[allow(dead_code)]
-    pub fn print_debug(&self)
+    fn fetch_instruction(&self) -> u16
+    {
+        // Memory sizes are powers of 2
+        let mask = self.mem.len() - 1;
+        let bit1 = self.mem[self.program_counter & mask];
+        let bit2 = self.mem[(self.program_counter + 1) & mask];
+        ((bit1 as u16) << 8) | bit2 as u16
+    }
+
+    pub fn screen(&self) -> &Screen
     {
-        println!("Chip8 {{ v_registers:{:X?} program_counter:{:X?} stack_pointer:{:X?} stack:{:X?} index:{:X?}}} dt:{}, st:{}",
-                    self.v_registers, self.program_counter, self.stack_pointer, self.stack, self.index, self.dt, self.st);
+        &self.screen_memory
     }
 
-    #[allow(dead_code)]
-    pub fn print_slice_mem(&self, a: usize, b: usize)
+    // XO-CHIP sound, 128 1 bit samples and the rate they are played at in Hz,
+    // 4000*2^((pitch-64)/48)
+    pub fn audio_pattern(&self) -> Option<(&[u8; 0x10], f32)>
     {
-        for (index, value) in (&self.mem[a..(b + a)]).iter().enumerate()
+        if self.variant != Variant::XoChip
         {
-                println!("{:#X}: {:#X}", index + a, value);
+            return None;
         }
+        Some((&self.audio_pattern, 4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)))
     }
 
-    fn fetch_instruction(&self) -> u16
+    // Replaces the random number generator, to make the run reproducible
+    pub fn set_rng(&mut self, rng: Rng)
     {
-        let bit1 = self.mem[self.program_counter as usize];
-        let bit2 = self.mem[(self.program_counter + 1) as usize];
-        ((bit1 as u16) << 8) | bit2 as u16
+        self.rng = rng;
     }
 
-    fn get_screen_buffer(&self) -> [u32; C8_SCREEN_WIDTH * C8_SCREEN_HEIGTH]
+    // Frames run so far, part of the save states so going back in time rewinds it
+    pub fn frame(&self) -> u64
     {
-        let mut buffer: [u32; C8_SCREEN_WIDTH * C8_SCREEN_HEIGTH] =
-                                    [0; C8_SCREEN_WIDTH * C8_SCREEN_HEIGTH];
-        for (y, row) in self.screen_memory[..].iter().enumerate()
+        self.frame
+    }
+
+    // True once a SUPER-CHIP program executed 00FD
+    pub fn has_exited(&self) -> bool
+    {
+        self.exited
+    }
+
+    // Returns whether the screen changed since the last call, clearing the flag
+    pub fn take_draw(&mut self) -> bool
+    {
+        let draw = self.draw;
+        self.draw = false;
+        draw
+    }
+
+    // Updates the state of a key of the hex keypad, a key going down while the
+    // machine is blocked on Fx0A gets stored in the waiting register
+    pub fn set_key(&mut self, hexcode: u8, pressed: bool)
+    {
+        let hexcode = (hexcode & 0xF) as usize;
+        if pressed && !self.keys[hexcode]
         {
-            for (x, bit) in row[..].iter().enumerate()
+            if let Some(register) = self.waiting_for_key
             {
-                let bit = *bit;
-                buffer[y*C8_SCREEN_WIDTH + x] = match bit
-                                                {
-                                                    true =>  0xFFFFFFFF,
-                                                    false => 0x00000000,
-                                                };
+                self.v_registers[register as usize] = hexcode as u8;
+                self.waiting_for_key = None;
             }
         }
-        buffer
+        self.keys[hexcode] = pressed;
     }
 
-    pub fn auto_run(&mut self) -> Result<()>
+    // Keys of the hex keypad held down, bit n being key n
+    pub fn keys_down(&self) -> u16
     {
-        let mut previous_draw_instant = Instant::now();
-        let mut previous_update_instant = Instant::now();
-        while self.window.is_open()
+        self.keys.iter().enumerate().fold(0, |keys, (hexcode, &pressed)| keys | (pressed as u16) << hexcode)
+    }
+
+    // Reads the state of the whole keypad from a frontend
+    pub fn update_keys(&mut self, keypad: &dyn Keypad)
+    {
+        for hexcode in 0..0x10
         {
-            //Update at 1000hz
-            if Instant::now().duration_since(previous_update_instant) > Duration::from_millis(1)
-            {
-                if let Some(keycode) = self.waiting_for_key
-                {
-                    if let Some(keys) = self.window.get_keys_pressed(KeyRepeat::No)
-                    {
-                        if let Some(key) = (*keys).iter().next()
-                        {
-                            if let Some(hexcode) = get_hexcode_from_key(*key)
-                            {
-                                self.v_registers[keycode as usize] = hexcode;
-                                self.waiting_for_key = None;
-                            }
-                        }
-                    }
-                }
-                else
-                {
+            self.set_key(hexcode, keypad.is_key_down(hexcode));
+        }
+    }
 
-                    let instruction = self.fetch_instruction();
-                    self.program_counter += 2;
-                    if self.program_counter >= 4096
-                    {
-                        bail!("program_counter can't be bigger than 4096");
-                    }
-                    self.execute_instruction(instruction)
-                                    .chain_err(||
-                                        format!("Error executing instruction {:#06X} at {:#06X}"
-                                            , instruction, self.program_counter - 2))?;
-                }
-                previous_update_instant = Instant::now();
-            }
+    pub fn is_buzzing(&self) -> bool
+    {
+        self.st > 0
+    }
 
-            // Draw at 60Hz
-            if Instant::now().duration_since(previous_draw_instant) > Duration::from_millis(1000/60)
+    // True when stepping won't execute an instruction
+    pub fn is_waiting(&self) -> bool
+    {
+        self.waiting_for_key.is_some() || self.waiting_for_vblank || self.exited
+    }
+
+    // Executes a single instruction, does nothing while waiting for a key or for the
+    // next frame after a draw with the display_wait quirk. On a fault the machine is
+    // left as it was before the instruction
+    pub fn step(&mut self) -> ::std::result::Result<(), Fault>
+    {
+        if self.is_waiting()
+        {
+            return Ok(());
+        }
+        self.mem_accesses.clear();
+        let (pc, stack_pointer, index) = (self.program_counter, self.stack_pointer, self.index);
+        let opcode = self.fetch_instruction();
+        self.program_counter += 2;
+        // The last instruction of memory has to go somewhere else. Anything else faults
+        // before it runs, its effects couldn't be undone
+        let mut result = if self.program_counter + 1 >= self.mem.len() && !self.wrap_pc &&
+                            !Chip8::leaves_sequence(opcode)
+        {
+            Err(FaultKind::PcOutOfRange(self.program_counter))
+        }
+        else
+        {
+            self.execute_instruction(opcode)
+        };
+        // There must be room for the next instruction. The instructions that can still
+        // get here only change the program counter, the stack and I, which are put back
+        if result.is_ok() && !self.exited && self.program_counter + 1 >= self.mem.len()
+        {
+            if self.wrap_pc
             {
-                self.dt = if self.dt > 0 { self.dt - 1} else { 0 };
-                self.st = if self.st > 0 { self.st - 1} else { 0 };
-                if self.draw
-                {
-                    self.window.update_with_buffer(&self.get_screen_buffer())
-                                                    .chain_err(|| "Error updating screen")?;
-                    previous_draw_instant = Instant::now();
-                }else
-                {
-                    self.window.update();
-                }
+                self.program_counter &= self.mem.len() - 1;
+            }
+            else
+            {
+                result = Err(FaultKind::PcOutOfRange(self.program_counter));
+            }
+        }
+        if result.is_ok()
+        {
+            if self.history.len() == HISTORY_LEN
+            {
+                self.history.pop_front();
             }
+            self.history.push_back((pc, opcode));
+        }
+        result.map_err(|kind|
+        {
+            self.program_counter = pc;
+            self.stack_pointer = stack_pointer;
+            self.index = index;
+            Fault { kind, pc, opcode }
+        })
+    }
+
+    // I after Fx55/Fx65 of registers V0 to Vx
+    fn increment_load_store(&mut self, x: u8)
+    {
+        if self.quirks.load_store_increment
+        {
+            let increment = if self.quirks.load_store_increment_x { x as usize } else { x as usize + 1 };
+            self.index = (self.index + increment) & 0xFFFF;
+        }
+    }
+
+    // Jumps, calls, returns and exits don't continue with the next instruction
+    fn leaves_sequence(opcode: u16) -> bool
+    {
+        match opcode >> 12
+        {
+            0x1 | 0x2 | 0xB => true,
+            _ => opcode == 0x00EE || opcode == 0x00FD,
+        }
+    }
+
+    // Jumps over the instruction at the program counter without executing it
+    pub fn skip_instruction(&mut self)
+    {
+        self.skip_next();
+        self.program_counter &= self.mem.len() - 1;
+    }
+
+    // Makes the addresses of memory accesses and of the program counter wrap around
+    // the memory instead of faulting
+    pub fn set_wrapping(&mut self, memory: bool, pc: bool)
+    {
+        self.wrap_memory = memory;
+        self.wrap_pc = pc;
+    }
+
+    pub fn set_memory_tracking(&mut self, track: bool)
+    {
+        self.track_memory = track;
+    }
+
+    // Memory read and written by the last instruction, only recorded with memory tracking
+    pub fn mem_accesses(&self) -> &[MemAccess]
+    {
+        &self.mem_accesses
+    }
+
+    // Fails unless the `len` bytes at `address` are in memory
+    fn check_range(&self, address: usize, len: usize) -> ::std::result::Result<(), FaultKind>
+    {
+        if !self.wrap_memory && address + len > self.mem.len()
+        {
+            return Err(FaultKind::MemoryOutOfRange(address.max(self.mem.len())));
         }
         Ok(())
     }
 
+    fn read_mem(&mut self, address: usize) -> ::std::result::Result<u8, FaultKind>
+    {
+        self.check_range(address, 1)?;
+        let address = address & (self.mem.len() - 1);
+        let value = self.mem[address];
+        if self.track_memory
+        {
+            self.mem_accesses.push(MemAccess { address, write: false, value });
+        }
+        Ok(value)
+    }
+
+    fn write_mem(&mut self, address: usize, value: u8) -> ::std::result::Result<(), FaultKind>
+    {
+        self.check_range(address, 1)?;
+        let address = address & (self.mem.len() - 1);
+        self.mem[address] = value;
+        if self.track_memory
+        {
+            self.mem_accesses.push(MemAccess { address, write: true, value });
+        }
+        Ok(())
+    }
+
+    // Decrements the delay and sound timers, must be called at 60Hz
+    pub fn tick_timers(&mut self)
+    {
+        self.waiting_for_vblank = false;
+        self.rng.tick();
+        self.frame += 1;
+        self.dt = if self.dt > 0 { self.dt - 1} else { 0 };
+        self.st = if self.st > 0 { self.st - 1} else { 0 };
+    }
+
+    // Skips the next instruction, which is 4 bytes long on XO-CHIP when it's F000 nnnn
+    fn skip_next(&mut self)
+    {
+        let long = self.variant == Variant::XoChip &&
+                    self.program_counter + 1 < self.mem.len() &&
+                    self.fetch_instruction() == 0xF000;
+        self.program_counter += if long { 4 } else { 2 };
+    }
+
     #[inline]
     fn get_addr(n1: u8, n2: u8, n3: u8) -> usize
     {
@@ -215,16 +468,21 @@ impl Chip8
         (n3 as usize)
     }
 
+    // Registers from x to y, in descending order when x > y
+    fn register_range(x: u8, y: u8) -> Box<dyn Iterator<Item = usize>>
+    {
+        let (x, y) = (x as usize, y as usize);
+        if x <= y { Box::new(x..=y) } else { Box::new((y..=x).rev()) }
+    }
+
     #[inline]
     fn get_kk(k1: u8, k2: u8) -> u8
     {
         (k1 << 4) | k2
     }
 
-    fn execute_instruction(&mut self, preinstruction: u16) -> Result<()>
+    fn execute_instruction(&mut self, preinstruction: u16) -> ::std::result::Result<(), FaultKind>
     {
-        //println!("{:X}", preinstruction);
-        //self.print_debug();
         let instruction =
         {
             (((preinstruction & 0xF000) >> 12) as u8,
@@ -235,17 +493,63 @@ impl Chip8
 
         match instruction
         {
+            //00Cn: SCD nibble
+            (0, 0, 0xC, n) if self.variant >= Variant::SuperChip =>
+            {
+                self.screen_memory.scroll_down(n as usize);
+                self.draw = true;
+            }
+            //00Dn: SCU nibble
+            (0, 0, 0xD, n) if self.variant == Variant::XoChip =>
+            {
+                self.screen_memory.scroll_up(n as usize);
+                self.draw = true;
+            }
             //00E0: CLS
             (0, 0, 0xE, 0) =>
             {
-                self.screen_memory = [[false; C8_SCREEN_WIDTH]; C8_SCREEN_HEIGTH];
+                self.screen_memory.clear();
+                self.draw = true;
             }
             //00EE: RET
             (0, 0, 0xE, 0xE) =>
             {
+                if self.stack_pointer == 0
+                {
+                    return Err(FaultKind::StackUnderflow);
+                }
                 self.program_counter = self.stack[self.stack_pointer];
                 self.stack_pointer -= 1;
             }
+            //00FB: SCR
+            (0, 0, 0xF, 0xB) if self.variant >= Variant::SuperChip =>
+            {
+                self.screen_memory.scroll_right(4);
+                self.draw = true;
+            }
+            //00FC: SCL
+            (0, 0, 0xF, 0xC) if self.variant >= Variant::SuperChip =>
+            {
+                self.screen_memory.scroll_left(4);
+                self.draw = true;
+            }
+            //00FD: EXIT
+            (0, 0, 0xF, 0xD) if self.variant >= Variant::SuperChip =>
+            {
+                self.exited = true;
+            }
+            //00FE: LOW
+            (0, 0, 0xF, 0xE) if self.variant >= Variant::SuperChip =>
+            {
+                self.screen_memory.set_hires(false);
+                self.draw = true;
+            }
+            //00FF: HIGH
+            (0, 0, 0xF, 0xF) if self.variant >= Variant::SuperChip =>
+            {
+                self.screen_memory.set_hires(true);
+                self.draw = true;
+            }
             //1nnn: JP addr
             (1, n1, n2, n3) =>
             {
@@ -256,7 +560,7 @@ impl Chip8
             {
                 if self.stack_pointer >= 15
                 {
-                    bail!("Stack is full");
+                    return Err(FaultKind::StackOverflow);
                 }
                 self.stack_pointer += 1;
                 self.stack[self.stack_pointer] = self.program_counter;
@@ -266,19 +570,37 @@ impl Chip8
             (3, x, k1, k2) =>
             {
                 if self.v_registers[x as usize] == Chip8::get_kk(k1, k2)
-                    {self.program_counter += 2;}
+                    {self.skip_next();}
             }
             //4xkk SNE Vx, byte
             (4, x, k1, k2) =>
             {
                 if self.v_registers[x as usize] != Chip8::get_kk(k1, k2)
-                    {self.program_counter += 2;}
+                    {self.skip_next();}
             }
             //5xy0 SE Vx, Vy
             (5, x, y, 0) =>
             {
                 if self.v_registers[x as usize] == self.v_registers[y as usize]
-                    {self.program_counter += 2;}
+                    {self.skip_next();}
+            }
+            //5xy2 LD [I], Vx - Vy
+            (5, x, y, 2) if self.variant == Variant::XoChip =>
+            {
+                self.check_range(self.index, (x as isize - y as isize).unsigned_abs() + 1)?;
+                for (offset, register) in Chip8::register_range(x, y).enumerate()
+                {
+                    self.write_mem(self.index + offset, self.v_registers[register])?;
+                }
+            }
+            //5xy3 LD Vx - Vy, [I]
+            (5, x, y, 3) if self.variant == Variant::XoChip =>
+            {
+                self.check_range(self.index, (x as isize - y as isize).unsigned_abs() + 1)?;
+                for (offset, register) in Chip8::register_range(x, y).enumerate()
+                {
+                    self.v_registers[register] = self.read_mem(self.index + offset)?;
+                }
             }
             //6xkk LD Vx, byte
             (6, x, k1, k2) =>
@@ -300,16 +622,28 @@ impl Chip8
             (8, x, y, 1) =>
             {
                 self.v_registers[x as usize] |= self.v_registers[y as usize];
+                if self.quirks.vf_reset
+                {
+                    self.v_registers[0xF] = 0;
+                }
             }
             //8xy2 AND Vx, Vy
             (8, x, y, 2) =>
             {
                 self.v_registers[x as usize] &= self.v_registers[y as usize];
+                if self.quirks.vf_reset
+                {
+                    self.v_registers[0xF] = 0;
+                }
             }
             //8xy3 XOR Vx, Vy
             (8, x, y, 3) =>
             {
                 self.v_registers[x as usize] ^= self.v_registers[y as usize];
+                if self.quirks.vf_reset
+                {
+                    self.v_registers[0xF] = 0;
+                }
             }
             //8xy4 ADD Vx, Vy, VF = carry
             (8, x, y, 4) =>
@@ -318,39 +652,42 @@ impl Chip8
                 self.v_registers[x as usize] = r.0;
                 self.v_registers[0xF] = r.1 as u8;
             }
-            //8xy5 SUB Vx, Vy, VF = NOT borrow (overflow)
+            //8xy5 SUB Vx, Vy, VF = NOT borrow
+            // The flag is written last, so it's what's left in VF when x is F
             (8, x, y, 5) =>
             {
-                self.v_registers[0xF] =
-                    (self.v_registers[x as usize] > self.v_registers[y as usize]) as u8;
-                self.v_registers[x as usize] =
-                    self.v_registers[x as usize].wrapping_sub(self.v_registers[y as usize]);
+                let (vx, vy) = (self.v_registers[x as usize], self.v_registers[y as usize]);
+                self.v_registers[x as usize] = vx.wrapping_sub(vy);
+                self.v_registers[0xF] = (vx >= vy) as u8;
             }
-            //8xy6 SHR Vx
-            (8, x, _y, 6) =>
+            //8xy6 SHR Vx {, Vy}
+            (8, x, y, 6) =>
             {
-                self.v_registers[0xF] = (self.v_registers[x as usize] & 1 == 1) as u8;
-                self.v_registers[x as usize] >>= 1;
+                let value = if self.quirks.shift_vy { self.v_registers[y as usize] }
+                            else { self.v_registers[x as usize] };
+                self.v_registers[x as usize] = value >> 1;
+                self.v_registers[0xF] = value & 1;
             }
-            //8xy7 SUBN Vx, Vy, VF = NOT borrow (overflow)
+            //8xy7 SUBN Vx, Vy, VF = NOT borrow
             (8, x, y, 7) =>
             {
-                self.v_registers[0xF] =
-                    (self.v_registers[y as usize] > self.v_registers[x as usize]) as u8;
-                self.v_registers[x as usize] =
-                    self.v_registers[y as usize].wrapping_sub(self.v_registers[x as usize]);
+                let (vx, vy) = (self.v_registers[x as usize], self.v_registers[y as usize]);
+                self.v_registers[x as usize] = vy.wrapping_sub(vx);
+                self.v_registers[0xF] = (vy >= vx) as u8;
             }
-            //8xyE SHL Vx
-            (8, x, _y, 0xE) =>
+            //8xyE SHL Vx {, Vy}
+            (8, x, y, 0xE) =>
             {
-                self.v_registers[0xF] = (self.v_registers[x as usize] & 128 == 128) as u8;
-                self.v_registers[x as usize] <<= 1;
+                let value = if self.quirks.shift_vy { self.v_registers[y as usize] }
+                            else { self.v_registers[x as usize] };
+                self.v_registers[x as usize] = value << 1;
+                self.v_registers[0xF] = value >> 7;
             }
             //9xy0 SNE Vx, Vy
             (9, x, y, 0) =>
             {
                 if self.v_registers[x as usize] != self.v_registers[y as usize]
-                    { self.program_counter += 2; }
+                    { self.skip_next(); }
             }
             //Annn LD I, addr
             (0xA, n1, n2, n3) =>
@@ -360,73 +697,109 @@ impl Chip8
             //Bnnn JP V0, addr
             (0xB, n1, n2, n3) =>
             {
-                self.program_counter = Chip8::get_addr(n1, n2, n3) + self.v_registers[0x0] as usize;
+                let offset = if self.quirks.jump_vx { self.v_registers[n1 as usize] }
+                             else { self.v_registers[0x0] };
+                self.program_counter = Chip8::get_addr(n1, n2, n3) + offset as usize;
             }
             //Cxkk RND Vx, byte
             (0xC, x, k1, k2) =>
             {
                 self.v_registers[x as usize] =
-                    rand::random::<u8>() & Chip8::get_kk(k1, k2);
+                    self.rng.next_byte() & Chip8::get_kk(k1, k2);
             }
-            //Dxyn DRw Vx, Vy, nibble
+            //Dxyn DRW Vx, Vy, nibble
+            //Dxy0 DRW Vx, Vy, 0 (SUPER-CHIP 16x16 sprite)
             (0xD, x, y, n) =>
             {
-                // Get the x and y values from the v_registers
-                let initial_x = self.v_registers[x as usize] as usize;
-                let initial_y = self.v_registers[y as usize] as usize;
+                let (width, height) = (self.screen_memory.width(), self.screen_memory.height());
+                // Get the x and y values from the v_registers, the starting position
+                // always wraps around the screen
+                let initial_x = self.v_registers[x as usize] as usize % width;
+                let initial_y = self.v_registers[y as usize] as usize % height;
+
+                // SUPER-CHIP sprites are 16 rows of 2 bytes
+                let (rows, bytes_per_row) = if n == 0 && self.variant >= Variant::SuperChip
+                                            { (16, 2) } else { (n as usize, 1) };
 
                 let mut flag: u8 = 0;
 
-                // Iterate through rows (index) to (index + n) byte index indicates the byte from
-                // memory which will drawn
-                for (y_offset, byte_index) in (self.index..(n as usize + self.index)).enumerate()
+                // On XO-CHIP there is a sprite for every selected plane, one after the other
+                let mut sprite_addr = self.index;
+                let planes = self.screen_memory.planes();
+                self.check_range(sprite_addr, planes.count_ones() as usize * rows * bytes_per_row)?;
+                for plane in [1u8, 2].iter().filter(|p| planes & **p != 0)
                 {
-                    // Iterate through the bits in the sprite byte
-                    let byte = BitIteratoru8::new(self.mem[byte_index]);
-                    for (x_offset, bit) in byte.enumerate()
+                    // Iterate through the rows of the sprite
+                    for y_offset in 0..rows
                     {
-                        // Adding the offset to the initial coords
-                        let x: usize = (initial_x + x_offset) % C8_SCREEN_WIDTH as usize;
-                        let y: usize = (initial_y + y_offset) % C8_SCREEN_HEIGTH as usize;
-                        // If bits are overlapped, set VF to 1
-                        if self.screen_memory[y][x] & bit
+                        let row_addr = sprite_addr + y_offset * bytes_per_row;
+                        // Iterate through the bits in the bytes of the row
+                        let mut bits = Vec::with_capacity(bytes_per_row * 8);
+                        for b in 0..bytes_per_row
+                        {
+                            bits.extend(BitIteratoru8::new(self.read_mem(row_addr + b)?));
+                        }
+                        for (x_offset, bit) in bits.into_iter().enumerate()
                         {
-                            flag = 1;
+                            // Adding the offset to the initial coords
+                            let x: usize = initial_x + x_offset;
+                            let y: usize = initial_y + y_offset;
+                            if !bit || (self.quirks.clip_sprites && (x >= width || y >= height))
+                            {
+                                continue;
+                            }
+                            // If bits are overlapped, set VF to 1
+                            if self.screen_memory.toggle(x % width, y % height, *plane)
+                            {
+                                flag = 1;
+                            }
                         }
-                        self.screen_memory[y][x] ^= bit;
                     }
+                    sprite_addr += rows * bytes_per_row;
                 }
                 self.v_registers[0xF] = flag;
                 self.draw = true;
+                self.waiting_for_vblank = self.quirks.display_wait;
             }
             //Ex9E SKP Vx
             (0xE, x, 0x9, 0xE) =>
             {
-                if let Some(key) = get_key_from_hexcode(self.v_registers[x as usize])
+                match self.keys.get(self.v_registers[x as usize] as usize)
                 {
-                    if self.window.is_key_down(key)
-                    {
-                        self.program_counter += 2;
-                    }
-                }else
-                {
-                    eprintln!("Instruction: {:X}, Hexcode {:X} in Register: V{:X} doesn't correspond to any key"
-                                , preinstruction, self.v_registers[x as usize], x);
+                    Some(true) => self.skip_next(),
+                    Some(false) => {}
+                    None => return Err(FaultKind::InvalidKey(self.v_registers[x as usize])),
                 }
             }
             //Ex9E SKNP Vx
             (0xE, x, 0xA, 0x1) =>
             {
-                if let Some(key) = get_key_from_hexcode(self.v_registers[x as usize])
+                match self.keys.get(self.v_registers[x as usize] as usize)
                 {
-                    if !self.window.is_key_down(key)
-                    {
-                        self.program_counter += 2;
-                    }
-                }else
+                    Some(false) => self.skip_next(),
+                    Some(true) => {}
+                    None => return Err(FaultKind::InvalidKey(self.v_registers[x as usize])),
+                }
+            }
+            //F000 nnnn LD I, long addr
+            (0xF, 0, 0, 0) if self.variant == Variant::XoChip =>
+            {
+                self.check_range(self.program_counter, 2)?;
+                self.index = self.fetch_instruction() as usize;
+                self.program_counter += 2;
+            }
+            //Fn01 PLANE n
+            (0xF, n, 0x0, 0x1) if self.variant == Variant::XoChip =>
+            {
+                self.screen_memory.set_planes(n);
+            }
+            //F002 AUDIO
+            (0xF, 0, 0x0, 0x2) if self.variant == Variant::XoChip =>
+            {
+                self.check_range(self.index, 0x10)?;
+                for i in 0..0x10
                 {
-                    bail!(format!("Hexcode {:X} in Register: V{:X} doesn't correspond to any key"
-                                , self.v_registers[x as usize], x));
+                    self.audio_pattern[i] = self.read_mem(self.index + i)?;
                 }
             }
             //Fx07 LD Vx, DT
@@ -452,44 +825,72 @@ impl Chip8
             //Fx1E ADD I, Vx
             (0xF, x, 0x1, 0xE) =>
             {
-                self.index = self.index + self.v_registers[x as usize] as usize;
+                // I is a 16 bit register
+                self.index = (self.index + self.v_registers[x as usize] as usize) & 0xFFFF;
             }
             //Fx29 LD F, Vx
             (0xF, x, 0x2, 0x9) =>
             {
                 self.index = self.v_registers[x as usize] as usize * 5;
             }
+            //Fx30 LD HF, Vx
+            (0xF, x, 0x3, 0x0) if self.variant >= Variant::SuperChip =>
+            {
+                self.index = BIG_FONT_ADDR + (self.v_registers[x as usize] & 0xF) as usize * 10;
+            }
+            //Fx3A PITCH Vx
+            (0xF, x, 0x3, 0xA) if self.variant == Variant::XoChip =>
+            {
+                self.pitch = self.v_registers[x as usize];
+            }
             //Fx33 LD B, Vx
             (0xF, x, 0x3, 0x3) =>
             {
                 let hundreds_digit = self.v_registers[x as usize] / 100;
                 let tens_digits = (self.v_registers[x as usize] - hundreds_digit * 100) / 10;
                 let ones_digits = self.v_registers[x as usize] - tens_digits * 10 - hundreds_digit * 100;
-                self.mem[self.index] = hundreds_digit;
-                self.mem[self.index + 1] = tens_digits;
-                self.mem[self.index + 2] = ones_digits;
+                self.check_range(self.index, 3)?;
+                self.write_mem(self.index, hundreds_digit)?;
+                self.write_mem(self.index + 1, tens_digits)?;
+                self.write_mem(self.index + 2, ones_digits)?;
             }
             //Fx55 LD [I]. Vx
             (0xF, x, 0x5, 0x5) =>
             {
+                self.check_range(self.index, x as usize + 1)?;
                 for i in 0..=x
                 {
                     let i = i as usize;
-                    self.mem[i + self.index] = self.v_registers[i];
+                    self.write_mem(i + self.index, self.v_registers[i])?;
                 }
+                self.increment_load_store(x);
             }
             //Fx65 LD Vx, [I]
             (0xF, x, 0x6, 0x5) =>
             {
+                self.check_range(self.index, x as usize + 1)?;
                 for i in 0..=x
                 {
                     let i = i as usize;
-                    self.v_registers[i] = self.mem[i + self.index];
+                    self.v_registers[i] = self.read_mem(i + self.index)?;
                 }
+                self.increment_load_store(x);
+            }
+            //Fx75 LD R, Vx
+            (0xF, x, 0x7, 0x5) if self.variant >= Variant::SuperChip =>
+            {
+                let x = x as usize;
+                self.rpl_flags[..=x].copy_from_slice(&self.v_registers[..=x]);
+            }
+            //Fx85 LD Vx, R
+            (0xF, x, 0x8, 0x5) if self.variant >= Variant::SuperChip =>
+            {
+                let x = x as usize;
+                self.v_registers[..=x].copy_from_slice(&self.rpl_flags[..=x]);
             }
             _ =>
             {
-                bail!("Unrecognized instruction");
+                return Err(FaultKind::InvalidOpcode);
             }
         }
         Ok(())
@@ -523,3 +924,139 @@ impl Iterator for BitIteratoru8
         Some(bit == 1)
     }
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::{Chip8, Quirks, Variant};
+    use super::fault::FaultKind;
+
+    // Runs `frames` frames of 16 instructions, the way the runner does without hooks
+    pub fn run_frames(chip: &mut Chip8, frames: usize)
+    {
+        for _ in 0..frames
+        {
+            for _ in 0..16
+            {
+                chip.step().unwrap();
+            }
+            chip.tick_timers();
+        }
+    }
+
+    #[test]
+    fn step_executes_one_instruction()
+    {
+        // LD V0, 5 ; LD V1, 7 ; JP 0x202
+        let rom = [0x60, 0x05, 0x61, 0x07, 0x12, 0x02];
+        let mut chip = Chip8::new(Some(&rom), Variant::Chip8, Quirks::default()).unwrap();
+        chip.step().unwrap();
+        assert_eq!(chip.program_counter, 0x202);
+        assert_eq!(chip.v_registers[0], 5);
+        assert_eq!(chip.v_registers[1], 0);
+        chip.step().unwrap();
+        assert_eq!(chip.program_counter, 0x204);
+        assert_eq!(chip.v_registers[1], 7);
+        chip.step().unwrap();
+        assert_eq!(chip.program_counter, 0x202);
+    }
+
+    // Registers after running `rom` with V1 = 5 and VF = `vf`
+    fn registers_after(rom: &[u8], vf: u8) -> [u8; 0x10]
+    {
+        let mut chip = Chip8::new(Some(rom), Variant::Chip8, Quirks::default()).unwrap();
+        chip.v_registers[1] = 5;
+        chip.v_registers[0xF] = vf;
+        chip.step().unwrap();
+        chip.v_registers
+    }
+
+    #[test]
+    fn subtraction_flag()
+    {
+        // SUB V1, V1 and SUBN V1, V1 don't borrow
+        let v = registers_after(&[0x81, 0x15], 0);
+        assert_eq!((v[1], v[0xF]), (0, 1));
+        let v = registers_after(&[0x81, 0x17], 0);
+        assert_eq!((v[1], v[0xF]), (0, 1));
+        // The flag replaces the difference when x is F
+        assert_eq!(registers_after(&[0x8F, 0x15], 3)[0xF], 0);
+        assert_eq!(registers_after(&[0x8F, 0x15], 7)[0xF], 1);
+        assert_eq!(registers_after(&[0x8F, 0x17], 3)[0xF], 1);
+        assert_eq!(registers_after(&[0x8F, 0x17], 7)[0xF], 0);
+        // SUB V0, V1 with V0 = 0 borrows
+        let v = registers_after(&[0x80, 0x15], 0);
+        assert_eq!((v[0], v[0xF]), (0xFB, 0));
+    }
+
+    #[test]
+    fn superchip_instructions()
+    {
+        // LD V0, 0xA ; LD HF, V0 ; LD V1, 1 ; LD V2, 2 ; LD R, V2 ; LD V1, 0 ; LD V2, 0 ;
+        // LD V2, R ; SCD 2 ; SCR ; SCL ; EXIT
+        let rom = [0x60, 0x0A, 0xF0, 0x30, 0x61, 0x01, 0x62, 0x02, 0xF2, 0x75, 0x61, 0x00,
+                   0x62, 0x00, 0xF2, 0x85, 0x00, 0xC2, 0x00, 0xFB, 0x00, 0xFC, 0x00, 0xFD];
+        let mut chip = Chip8::new(Some(&rom), Variant::SuperChip, Quirks::superchip()).unwrap();
+        chip.step().unwrap();
+        chip.step().unwrap();
+        assert_eq!(chip.index, 0x50 + 0xA * 10);
+        assert_eq!(chip.mem[chip.index..chip.index + 2], [0x7E, 0xFF]);
+        for _ in 0..6
+        {
+            chip.step().unwrap();
+        }
+        assert_eq!(chip.v_registers[..3], [0xA, 1, 2]);
+        chip.screen_memory.toggle(5, 5, 1);
+        chip.step().unwrap();
+        assert_eq!(chip.screen_memory.get(5, 7), 1);
+        chip.step().unwrap();
+        assert_eq!(chip.screen_memory.get(9, 7), 1);
+        chip.step().unwrap();
+        assert_eq!(chip.screen_memory.get(5, 7), 1);
+        chip.step().unwrap();
+        assert!(chip.has_exited());
+        // None of them exist on CHIP-8
+        let mut chip = Chip8::new(Some(&[0x00, 0xFB]), Variant::Chip8, Quirks::default()).unwrap();
+        assert_eq!(chip.step().unwrap_err().kind, FaultKind::InvalidOpcode);
+    }
+
+    #[test]
+    fn xochip_instructions()
+    {
+        let rom = [0x60, 0x11, 0x61, 0x22, 0x62, 0x33, // LD V0-V2, 0x11 0x22 0x33
+                   0xF0, 0x00, 0x04, 0x00,             // LD I, LONG 0x400
+                   0x50, 0x22,                         // LD [I], V0 - V2
+                   0x60, 0x00, 0x61, 0x00, 0x62, 0x00, // LD V0-V2, 0
+                   0x52, 0x03,                         // LD V2 - V0, [I]
+                   0x65, 0x00, 0x35, 0x00,             // LD V5, 0 ; SE V5, 0
+                   0xF0, 0x00, 0x12, 0x34,             // LD I, LONG 0x1234 (skipped)
+                   0xF3, 0x01,                         // PLANE 3
+                   0xF0, 0x00, 0x02, 0x30,             // LD I, LONG sprite
+                   0xD5, 0x51,                         // DRW V5, V5, 1
+                   0xF2, 0x01, 0x00, 0xE0,             // PLANE 2 ; CLS
+                   0x64, 0x50, 0xF4, 0x3A,             // LD V4, 0x50 ; PITCH V4
+                   0xF0, 0x02,                         // AUDIO
+                   0x12, 0x2E,                         // JP 0x22E
+                   0x80, 0xC0];                        // sprite: a row per plane
+        let mut chip = Chip8::new(Some(&rom), Variant::XoChip, Quirks::xochip()).unwrap();
+        let run = |chip: &mut Chip8, steps| for _ in 0..steps { chip.step().unwrap(); };
+        run(&mut chip, 5);
+        assert_eq!(chip.index, 0x400);
+        assert_eq!(chip.mem[0x400..0x403], [0x11, 0x22, 0x33]);
+        run(&mut chip, 4);
+        assert_eq!(chip.v_registers[..3], [0x33, 0x22, 0x11]);
+        run(&mut chip, 2);
+        assert_eq!(chip.program_counter, 0x21C);
+        assert_eq!(chip.index, 0x400);
+        run(&mut chip, 3);
+        assert_eq!((chip.screen_memory.get(0, 0), chip.screen_memory.get(1, 0)), (3, 2));
+        assert_eq!(chip.v_registers[0xF], 0);
+        run(&mut chip, 2);
+        assert_eq!((chip.screen_memory.get(0, 0), chip.screen_memory.get(1, 0)), (1, 0));
+        run(&mut chip, 3);
+        let (pattern, rate) = chip.audio_pattern().unwrap();
+        assert_eq!(pattern[..3], [0x80, 0xC0, 0x00]);
+        // 4000 * 2^(1/3)
+        assert!((rate - 5039.68).abs() < 0.01);
+    }
+}
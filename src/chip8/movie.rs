@@ -0,0 +1,327 @@
+use std::fs;
+use super::{Chip8, Quirks, RngKind, Variant, Result, ResultExt};
+use super::frontend::Keypad;
+use super::state::{crc32, variant_id, variant_from_id, StateReader, StateWriter};
+
+// Movie layout, all the numbers are little endian:
+//  magic "C8MV" | version: u16 | header | frame count: u32 | frames | hash count: u32 | hashes
+// The header holds everything the run depends on besides the keys:
+//  crc32 of the rom: u32 | variant: u8 | quirks: u8 | rng: u8 | seed: u32 | hash interval: u16
+// Every frame is the keys held down (bit n for key n): u16 | instructions run: u16
+// and every `hash interval` frames the crc32 of the save state of the machine is stored
+const MOVIE_MAGIC: &[u8; 4] = b"C8MV";
+const MOVIE_VERSION: u16 = 2;
+// Frames between two checks of the state of the machine, every frame so a desync is
+// found where it happens
+const HASH_INTERVAL: u16 = 1;
+
+// Settings of the machine a movie was recorded with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MovieHeader
+{
+    pub rom_hash: u32,
+    pub variant: Variant,
+    pub quirks: Quirks,
+    pub rng_kind: RngKind,
+    pub seed: u32,
+}
+
+impl MovieHeader
+{
+    pub fn new(rom: &[u8], variant: Variant, quirks: Quirks, rng_kind: RngKind, seed: u32) -> MovieHeader
+    {
+        MovieHeader { rom_hash: crc32(rom), variant, quirks, rng_kind, seed }
+    }
+}
+
+fn quirks_bits(quirks: Quirks) -> u8
+{
+    [quirks.shift_vy, quirks.load_store_increment, quirks.jump_vx,
+     quirks.vf_reset, quirks.clip_sprites, quirks.display_wait, quirks.load_store_increment_x]
+        .iter().enumerate().fold(0, |bits, (bit, &set)| bits | (set as u8) << bit)
+}
+
+fn quirks_from_bits(bits: u8) -> Quirks
+{
+    let set = |bit: u8| bits & (1 << bit) != 0;
+    Quirks
+    {
+        shift_vy: set(0),
+        load_store_increment: set(1),
+        jump_vx: set(2),
+        vf_reset: set(3),
+        clip_sprites: set(4),
+        display_wait: set(5),
+        load_store_increment_x: set(6),
+    }
+}
+
+fn state_hash(chip: &Chip8) -> u32
+{
+    crc32(&chip.save_state())
+}
+
+// Keys of every frame of a run, enough to play it again
+pub struct Movie
+{
+    pub header: MovieHeader,
+    hash_interval: u16,
+    // Keys held down and instructions run on every frame
+    frames: Vec<(u16, u16)>,
+    // State of the machine after every hash_interval frames
+    hashes: Vec<u32>,
+}
+
+impl Movie
+{
+    pub fn new(header: MovieHeader) -> Movie
+    {
+        Movie { header, hash_interval: HASH_INTERVAL, frames: Vec::new(), hashes: Vec::new() }
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.frames.len()
+    }
+
+    pub fn save(&self, path: &str) -> Result<()>
+    {
+        let mut out = StateWriter::new();
+        out.bytes(MOVIE_MAGIC);
+        out.u16(MOVIE_VERSION);
+        out.u32(self.header.rom_hash);
+        out.u8(variant_id(self.header.variant));
+        out.u8(quirks_bits(self.header.quirks));
+        out.u8(match self.header.rng_kind
+        {
+            RngKind::Xorshift => 0,
+            RngKind::CosmacVip => 1,
+        });
+        out.u32(self.header.seed);
+        out.u16(self.hash_interval);
+        out.u32(self.frames.len() as u32);
+        for (keys, cycles) in self.frames.iter()
+        {
+            out.u16(*keys);
+            out.u16(*cycles);
+        }
+        out.u32(self.hashes.len() as u32);
+        for hash in self.hashes.iter()
+        {
+            out.u32(*hash);
+        }
+        fs::write(path, out.into_inner()).chain_err(|| format!("Couldn't write movie {}", path))
+    }
+
+    pub fn load(path: &str) -> Result<Movie>
+    {
+        let data = fs::read(path).chain_err(|| format!("Couldn't read movie {}", path))?;
+        if data.len() < 4 || &data[0..4] != MOVIE_MAGIC
+        {
+            bail!(format!("{} is not a movie", path));
+        }
+        Movie::read(&mut StateReader::new(&data[4..])).chain_err(|| format!("Movie {} is corrupted", path))
+    }
+
+    fn read(data: &mut StateReader) -> Result<Movie>
+    {
+        let version = data.u16()?;
+        if version != MOVIE_VERSION
+        {
+            bail!(format!("Movie version {} is not supported, this interpreter only plays version {}",
+                            version, MOVIE_VERSION));
+        }
+        let rom_hash = data.u32()?;
+        let variant = match variant_from_id(data.u8()?)
+        {
+            Some(variant) => variant,
+            None => bail!("Unknown mode"),
+        };
+        let quirks = quirks_from_bits(data.u8()?);
+        let rng_kind = match data.u8()?
+        {
+            0 => RngKind::Xorshift,
+            1 => RngKind::CosmacVip,
+            _ => bail!("Unknown random number generator"),
+        };
+        let seed = data.u32()?;
+        let hash_interval = data.u16()?;
+        if hash_interval == 0
+        {
+            bail!("Hash interval can't be 0");
+        }
+        let frame_count = data.u32()?;
+        let mut frames = Vec::new();
+        for _ in 0..frame_count
+        {
+            frames.push((data.u16()?, data.u16()?));
+        }
+        let hash_count = data.u32()?;
+        let mut hashes = Vec::new();
+        for _ in 0..hash_count
+        {
+            hashes.push(data.u32()?);
+        }
+        if !data.is_empty()
+        {
+            bail!("Unexpected data at the end");
+        }
+        Ok(Movie
+        {
+            header: MovieHeader { rom_hash, variant, quirks, rng_kind, seed },
+            hash_interval,
+            frames,
+            hashes,
+        })
+    }
+}
+
+// Writes down the keys of every frame as the machine runs
+pub struct MovieRecorder
+{
+    movie: Movie,
+}
+
+impl MovieRecorder
+{
+    pub fn new(header: MovieHeader) -> MovieRecorder
+    {
+        MovieRecorder { movie: Movie::new(header) }
+    }
+
+    // Called when `frame` starts, again if the debugger stopped it halfway
+    pub fn record_frame(&mut self, frame: u64, keys: u16, cycles: usize)
+    {
+        self.movie.frames.truncate(frame as usize);
+        self.movie.frames.push((keys, cycles as u16));
+    }
+
+    // Called after `frames` frames ran
+    pub fn record_state(&mut self, frames: u64, chip: &Chip8)
+    {
+        let interval = self.movie.hash_interval as u64;
+        if frames.is_multiple_of(interval)
+        {
+            self.movie.hashes.truncate((frames / interval - 1) as usize);
+            self.movie.hashes.push(state_hash(chip));
+        }
+    }
+
+    pub fn into_movie(self) -> Movie
+    {
+        self.movie
+    }
+}
+
+// Plays the keys of a movie back, checking that the machine goes through the same
+// states it went through when it was recorded
+pub struct MoviePlayer
+{
+    movie: Movie,
+    keys: u16,
+    cycles: usize,
+}
+
+impl MoviePlayer
+{
+    pub fn new(movie: Movie) -> MoviePlayer
+    {
+        MoviePlayer { movie, keys: 0, cycles: 0 }
+    }
+
+    // Moves to `frame`, no keys are held after the end of the movie
+    pub fn advance(&mut self, frame: u64)
+    {
+        match self.movie.frames.get(frame as usize)
+        {
+            Some(&(keys, cycles)) =>
+            {
+                self.keys = keys;
+                self.cycles = cycles as usize;
+            }
+            None => self.keys = 0,
+        }
+    }
+
+    // Instructions the current frame ran when recorded, None before the first frame
+    pub fn cycles_per_frame(&self) -> Option<usize>
+    {
+        if self.cycles > 0 { Some(self.cycles) } else { None }
+    }
+
+    // Called after `frames` frames ran, fails at the first check that doesn't match
+    pub fn check_state(&self, frames: u64, chip: &Chip8) -> Result<()>
+    {
+        let interval = self.movie.hash_interval as u64;
+        if !frames.is_multiple_of(interval)
+        {
+            return Ok(());
+        }
+        if let Some(&hash) = self.movie.hashes.get((frames / interval - 1) as usize)
+        {
+            if hash != state_hash(chip)
+            {
+                if interval == 1
+                {
+                    bail!(format!("Replay diverged from the movie at frame {}", frames));
+                }
+                bail!(format!("Replay diverged from the movie by frame {}, it was still in sync at frame {}",
+                                frames, frames - interval));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Keypad for MoviePlayer
+{
+    fn is_key_down(&self, hexcode: u8) -> bool
+    {
+        self.keys & (1 << (hexcode & 0xF)) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{MovieHeader, MoviePlayer, MovieRecorder};
+    use super::super::{Chip8, Quirks, Rng, RngKind, Variant};
+    use super::super::tests::run_frames;
+
+    #[test]
+    fn desync_is_found_at_its_frame()
+    {
+        let rom = include_bytes!("../../roms/BRIX");
+        let quirks = Quirks::default();
+        let mut recorder = MovieRecorder::new(MovieHeader::new(rom, Variant::Chip8, quirks, RngKind::Xorshift, 1));
+        let mut chip = Chip8::new(Some(rom), Variant::Chip8, quirks).unwrap();
+        chip.set_rng(Rng::new(RngKind::Xorshift, 1));
+        for frame in 0..20
+        {
+            recorder.record_frame(frame, 0, 16);
+            run_frames(&mut chip, 1);
+            recorder.record_state(frame + 1, &chip);
+        }
+        let player = MoviePlayer::new(recorder.into_movie());
+        let mut replay = Chip8::new(Some(rom), Variant::Chip8, quirks).unwrap();
+        replay.set_rng(Rng::new(RngKind::Xorshift, 1));
+        for frame in 1..=20
+        {
+            run_frames(&mut replay, 1);
+            if frame == 12
+            {
+                replay.v_registers[0xE] ^= 1;
+            }
+            match player.check_state(frame, &replay)
+            {
+                Ok(()) => assert!(frame < 12, "frame {} is in sync", frame),
+                Err(e) =>
+                {
+                    assert_eq!(e.to_string(), "Replay diverged from the movie at frame 12");
+                    return;
+                }
+            }
+        }
+        panic!("The desync wasn't found");
+    }
+}
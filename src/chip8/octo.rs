@@ -0,0 +1,1119 @@
+use std::collections::{HashMap, VecDeque};
+use super::{Result, PROGRAM_START};
+
+// Compiler for Octo (.8o) programs, the assembly language of the Octo IDE used by most
+// modern CHIP-8, SUPER-CHIP and XO-CHIP programs:
+// http://johnearnest.github.io/Octo/docs/Manual.html
+//
+// Like Octo it works in a single pass, addresses of labels used before being defined
+// are filled in at the end. Execution starts at the `main` label, a jump to it is put
+// at 0x200 unless `main` is the first thing in the program.
+
+type Fallible<T> = std::result::Result<T, String>;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Token
+{
+    text: String,
+    line: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Macro
+{
+    arguments: Vec<String>,
+    body: Vec<Token>,
+    calls: usize,
+}
+
+// How an address is written once the label is known
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FixupKind
+{
+    // Low 12 bits of an nnnn instruction
+    Address,
+    // Whole word, for `i := long`
+    Long,
+    // Byte made of a nibble and the highest 4 bits of the address, for :unpack
+    UnpackHigh(u8),
+    // Highest byte of the address, for :unpack long
+    High,
+    // Lowest byte of the address
+    Low,
+}
+
+#[derive(Clone, Debug)]
+struct Fixup
+{
+    address: usize,
+    label: String,
+    kind: FixupKind,
+    line: usize,
+}
+
+// Second operand of an arithmetic or comparison
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operand
+{
+    Register(u8),
+    Byte(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Comparison
+{
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    Key,
+    NotKey,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Condition
+{
+    register: u8,
+    comparison: Comparison,
+    operand: Operand,
+}
+
+impl Condition
+{
+    fn negate(self) -> Condition
+    {
+        let comparison = match self.comparison
+        {
+            Comparison::Equal => Comparison::NotEqual,
+            Comparison::NotEqual => Comparison::Equal,
+            Comparison::Less => Comparison::GreaterEqual,
+            Comparison::GreaterEqual => Comparison::Less,
+            Comparison::Greater => Comparison::LessEqual,
+            Comparison::LessEqual => Comparison::Greater,
+            Comparison::Key => Comparison::NotKey,
+            Comparison::NotKey => Comparison::Key,
+        };
+        Condition { comparison, ..self }
+    }
+}
+
+struct Loop
+{
+    start: usize,
+    // Jumps out of the loop made by `while`, pointed to the end at `again`
+    exits: Vec<usize>,
+}
+
+struct Compiler
+{
+    tokens: VecDeque<Token>,
+    line: usize,
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    // Jumps of `if ... begin` and `else` waiting for their `else` or `end`
+    branches: Vec<usize>,
+    loops: Vec<Loop>,
+    jump_to_main: bool,
+}
+
+// Compiles an Octo program into a rom loaded at PROGRAM_START
+pub fn assemble(source: &str) -> Result<Vec<u8>>
+{
+    let mut compiler = Compiler::new(source);
+    compiler.compile().map_err(|e| format!("Line {}: {}", compiler.line, e))?;
+    Ok(compiler.rom)
+}
+
+// Whitespace separated tokens, `#` starts a comment
+fn tokenize(source: &str) -> VecDeque<Token>
+{
+    let mut tokens = VecDeque::new();
+    for (n, line) in source.lines().enumerate()
+    {
+        let code = line.split('#').next().unwrap_or("");
+        for word in code.split_whitespace()
+        {
+            tokens.push_back(Token { text: word.to_string(), line: n + 1 });
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<f64>
+{
+    let (negative, digits) = match text.strip_prefix('-')
+    {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X"))
+    {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    }
+    else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B"))
+    {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    }
+    else if digits.starts_with(|c: char| c.is_ascii_digit())
+    {
+        digits.parse().ok()?
+    }
+    else
+    {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u8>
+{
+    let lower = text.to_lowercase();
+    let digit = lower.strip_prefix('v')?;
+    if digit.len() == 1
+    {
+        u8::from_str_radix(digit, 16).ok()
+    }
+    else
+    {
+        None
+    }
+}
+
+impl Compiler
+{
+    fn new(source: &str) -> Compiler
+    {
+        Compiler
+        {
+            tokens: tokenize(source),
+            line: 1,
+            // Room for the jump to main
+            rom: vec![0, 0],
+            here: PROGRAM_START + 2,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            branches: Vec::new(),
+            loops: Vec::new(),
+            jump_to_main: true,
+        }
+    }
+
+    fn compile(&mut self) -> Fallible<()>
+    {
+        while !self.tokens.is_empty()
+        {
+            self.statement()?;
+        }
+        if !self.branches.is_empty()
+        {
+            return Err("Missing end of a begin".to_string());
+        }
+        if !self.loops.is_empty()
+        {
+            return Err("Missing again at the end of a loop".to_string());
+        }
+        let main = match self.labels.get("main")
+        {
+            Some(&main) => main,
+            None => return Err("The program has no main label".to_string()),
+        };
+        if self.jump_to_main
+        {
+            self.write_word(PROGRAM_START, 0x1000 | main as u16)?;
+        }
+        for fixup in std::mem::take(&mut self.fixups)
+        {
+            self.line = fixup.line;
+            let address = match self.labels.get(&fixup.label)
+            {
+                Some(&address) => address,
+                None => return Err(format!("Undefined label {}", fixup.label)),
+            };
+            self.patch(fixup.address, address, fixup.kind)?;
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Fallible<String>
+    {
+        match self.tokens.pop_front()
+        {
+            Some(token) =>
+            {
+                self.line = token.line;
+                Ok(token.text)
+            }
+            None => Err("Unexpected end of the program".to_string()),
+        }
+    }
+
+    fn peek(&self) -> Option<&str>
+    {
+        self.tokens.front().map(|t| t.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Fallible<()>
+    {
+        let token = self.next()?;
+        if token == expected
+        {
+            Ok(())
+        }
+        else
+        {
+            Err(format!("Expected {} but found {}", expected, token))
+        }
+    }
+
+    fn name(&mut self) -> Fallible<String>
+    {
+        let name = self.next()?;
+        if parse_number(&name).is_some() || self.register_of(&name).is_some()
+        {
+            return Err(format!("{} can't be used as a name", name));
+        }
+        Ok(name)
+    }
+
+    fn register_of(&self, text: &str) -> Option<u8>
+    {
+        parse_register(text).or_else(|| self.aliases.get(text).cloned())
+    }
+
+    fn register(&mut self) -> Fallible<u8>
+    {
+        let token = self.next()?;
+        match self.register_of(&token)
+        {
+            Some(register) => Ok(register),
+            None => Err(format!("Expected a register but found {}", token)),
+        }
+    }
+
+    fn is_register(&self) -> bool
+    {
+        self.peek().is_some_and(|t| self.register_of(t).is_some())
+    }
+
+    // Number or constant, labels already defined included
+    fn value_of(&self, text: &str) -> Option<f64>
+    {
+        parse_number(text)
+            .or_else(|| self.constants.get(text).cloned())
+            .or_else(|| self.labels.get(text).map(|&a| a as f64))
+    }
+
+    fn number(&mut self, max: i64) -> Fallible<u16>
+    {
+        let token = self.next()?;
+        let value = match self.value_of(&token)
+        {
+            Some(value) => value.floor() as i64,
+            None => return Err(format!("Unknown value {}", token)),
+        };
+        // Negative bytes are stored as two's complement: v0 += -1
+        if max == 0xFF && (-0x80..0).contains(&value)
+        {
+            return Ok((value & 0xFF) as u16);
+        }
+        if value < 0 || value > max
+        {
+            return Err(format!("Value {} is out of range, it must be at most {:#X}", token, max));
+        }
+        Ok(value as u16)
+    }
+
+    fn byte(&mut self) -> Fallible<u8>
+    {
+        Ok(self.number(0xFF)? as u8)
+    }
+
+    fn nibble(&mut self) -> Fallible<u16>
+    {
+        self.number(0xF)
+    }
+
+    fn operand(&mut self) -> Fallible<Operand>
+    {
+        if self.is_register()
+        {
+            Ok(Operand::Register(self.register()?))
+        }
+        else
+        {
+            Ok(Operand::Byte(self.byte()?))
+        }
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Fallible<()>
+    {
+        let offset = self.here - PROGRAM_START;
+        if self.here > 0xFFFF
+        {
+            return Err("The program doesn't fit in memory".to_string());
+        }
+        if offset >= self.rom.len()
+        {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn emit(&mut self, word: u16) -> Fallible<()>
+    {
+        self.emit_byte((word >> 8) as u8)?;
+        self.emit_byte(word as u8)
+    }
+
+    fn xy(&mut self, opcode: u16, x: u8, y: u8) -> Fallible<()>
+    {
+        self.emit(opcode | (x as u16) << 8 | (y as u16) << 4)
+    }
+
+    fn write_word(&mut self, address: usize, word: u16) -> Fallible<()>
+    {
+        let offset = address - PROGRAM_START;
+        self.rom[offset] = (word >> 8) as u8;
+        self.rom[offset + 1] = word as u8;
+        Ok(())
+    }
+
+    fn patch(&mut self, at: usize, address: usize, kind: FixupKind) -> Fallible<()>
+    {
+        let offset = at - PROGRAM_START;
+        match kind
+        {
+            FixupKind::Address =>
+            {
+                if address > 0xFFF
+                {
+                    return Err(format!("Address {:#X} doesn't fit in 12 bits, use i := long", address));
+                }
+                self.rom[offset] = (self.rom[offset] & 0xF0) | (address >> 8) as u8;
+                self.rom[offset + 1] = address as u8;
+            }
+            FixupKind::Long => self.write_word(at, address as u16)?,
+            FixupKind::UnpackHigh(nibble) => self.rom[offset] = (nibble << 4) | ((address >> 8) & 0xF) as u8,
+            FixupKind::High => self.rom[offset] = (address >> 8) as u8,
+            FixupKind::Low => self.rom[offset] = address as u8,
+        }
+        Ok(())
+    }
+
+    // Writes the address of a label or value at `at`, now or once the label is defined
+    fn address(&mut self, at: usize, kind: FixupKind) -> Fallible<()>
+    {
+        let token = self.next()?;
+        self.resolve(&token, at, kind)
+    }
+
+    fn resolve(&mut self, token: &str, at: usize, kind: FixupKind) -> Fallible<()>
+    {
+        match self.value_of(token)
+        {
+            Some(value) if value >= 0.0 => self.patch(at, value as usize, kind),
+            Some(_) => Err(format!("Negative address {}", token)),
+            None =>
+            {
+                self.fixups.push(Fixup { address: at, label: token.to_string(), kind, line: self.line });
+                Ok(())
+            }
+        }
+    }
+
+    // Instruction taking a 12 bit address: jump, jump0, call...
+    fn address_instruction(&mut self, opcode: u16) -> Fallible<()>
+    {
+        let at = self.here;
+        self.emit(opcode)?;
+        self.address(at, FixupKind::Address)
+    }
+
+    // Jump whose target is filled in later
+    fn placeholder_jump(&mut self) -> Fallible<usize>
+    {
+        let at = self.here;
+        self.emit(0x1000)?;
+        Ok(at)
+    }
+
+    fn point_jump(&mut self, jump: usize, target: usize) -> Fallible<()>
+    {
+        self.patch(jump, target, FixupKind::Address)
+    }
+
+    fn define_label(&mut self, name: String) -> Fallible<()>
+    {
+        // No need for the jump when the program starts with main
+        if name == "main" && self.here == PROGRAM_START + 2 && self.rom.len() == 2 &&
+            self.labels.is_empty() && self.fixups.is_empty()
+        {
+            self.rom.clear();
+            self.here = PROGRAM_START;
+            self.jump_to_main = false;
+        }
+        if self.labels.contains_key(&name)
+        {
+            return Err(format!("Label {} is defined twice", name));
+        }
+        self.labels.insert(name, self.here);
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Fallible<()>
+    {
+        let token = self.next()?;
+        if let Some(register) = self.register_of(&token)
+        {
+            return self.register_statement(register);
+        }
+        match token.as_str()
+        {
+            ":" =>
+            {
+                let name = self.name()?;
+                self.define_label(name)?;
+            }
+            ":alias" =>
+            {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+            }
+            ":const" =>
+            {
+                let name = self.name()?;
+                let value_token = self.next()?;
+                let value = match self.value_of(&value_token)
+                {
+                    Some(value) => value,
+                    None => return Err(format!("Unknown value {}", value_token)),
+                };
+                self.constants.insert(name, value);
+            }
+            ":org" =>
+            {
+                let address = self.number(0xFFFF)? as usize;
+                if address < PROGRAM_START
+                {
+                    return Err(format!(":org {:#X} is before the start of the program", address));
+                }
+                self.here = address;
+            }
+            ":next" =>
+            {
+                // Label pointing at the second byte of the next instruction, for self
+                // modifying code
+                let name = self.name()?;
+                if self.labels.insert(name.clone(), self.here + 1).is_some()
+                {
+                    return Err(format!("Label {} is defined twice", name));
+                }
+            }
+            ":unpack" =>
+            {
+                // v0 := nibble and high bits of the address, v1 := low byte of the address
+                let high = if self.peek() == Some("long")
+                {
+                    self.next()?;
+                    FixupKind::High
+                }
+                else
+                {
+                    FixupKind::UnpackHigh(self.nibble()? as u8)
+                };
+                let label = self.next()?;
+                let at = self.here;
+                self.emit(0x6000)?;
+                self.emit(0x6100)?;
+                self.resolve(&label, at + 1, high)?;
+                self.resolve(&label, at + 3, FixupKind::Low)?;
+            }
+            ":byte" =>
+            {
+                let value = if self.peek() == Some("{")
+                {
+                    let expression = self.braces()?;
+                    calc_to_byte(self.calc(&expression)?)?
+                }
+                else
+                {
+                    self.byte()?
+                };
+                self.emit_byte(value)?;
+            }
+            ":call" => self.address_instruction(0x2000)?,
+            ":breakpoint" => { self.name()?; }
+            ":monitor" => { self.next()?; self.next()?; }
+            ":macro" => self.define_macro()?,
+            ":calc" =>
+            {
+                let name = self.name()?;
+                let expression = self.braces()?;
+                let value = self.calc(&expression)?;
+                self.constants.insert(name, value);
+            }
+            "clear" => self.emit(0x00E0)?,
+            "return" | ";" => self.emit(0x00EE)?,
+            "scroll-down" => { let n = self.nibble()?; self.emit(0x00C0 | n)?; }
+            "scroll-up" => { let n = self.nibble()?; self.emit(0x00D0 | n)?; }
+            "scroll-right" => self.emit(0x00FB)?,
+            "scroll-left" => self.emit(0x00FC)?,
+            "exit" => self.emit(0x00FD)?,
+            "lores" => self.emit(0x00FE)?,
+            "hires" => self.emit(0x00FF)?,
+            "jump" => self.address_instruction(0x1000)?,
+            "jump0" => self.address_instruction(0xB000)?,
+            "native" => self.address_instruction(0x0000)?,
+            "sprite" =>
+            {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.xy(0xD000 | n, x, y)?;
+            }
+            "plane" =>
+            {
+                let n = self.number(0x3)?;
+                self.emit(0xF001 | n << 8)?;
+            }
+            "audio" => self.emit(0xF002)?,
+            "delay" => { self.expect(":=")?; let x = self.register()?; self.xy(0xF015, x, 0)?; }
+            "buzzer" => { self.expect(":=")?; let x = self.register()?; self.xy(0xF018, x, 0)?; }
+            "pitch" => { self.expect(":=")?; let x = self.register()?; self.xy(0xF03A, x, 0)?; }
+            "bcd" => { let x = self.register()?; self.xy(0xF033, x, 0)?; }
+            "save" | "load" =>
+            {
+                let x = self.register()?;
+                if self.peek() == Some("-")
+                {
+                    self.next()?;
+                    let y = self.register()?;
+                    self.xy(if token == "save" { 0x5002 } else { 0x5003 }, x, y)?;
+                }
+                else
+                {
+                    self.xy(if token == "save" { 0xF055 } else { 0xF065 }, x, 0)?;
+                }
+            }
+            "saveflags" => { let x = self.register()?; self.xy(0xF075, x, 0)?; }
+            "loadflags" => { let x = self.register()?; self.xy(0xF085, x, 0)?; }
+            "i" => self.index_statement()?,
+            "if" =>
+            {
+                let condition = self.condition()?;
+                match self.next()?.as_str()
+                {
+                    "then" => self.skip_unless(condition)?,
+                    "begin" =>
+                    {
+                        self.skip_unless(condition.negate())?;
+                        let jump = self.placeholder_jump()?;
+                        self.branches.push(jump);
+                    }
+                    other => return Err(format!("Expected then or begin but found {}", other)),
+                }
+            }
+            "else" =>
+            {
+                let jump = match self.branches.pop()
+                {
+                    Some(jump) => jump,
+                    None => return Err("else without if ... begin".to_string()),
+                };
+                let end_jump = self.placeholder_jump()?;
+                let here = self.here;
+                self.point_jump(jump, here)?;
+                self.branches.push(end_jump);
+            }
+            "end" =>
+            {
+                let jump = match self.branches.pop()
+                {
+                    Some(jump) => jump,
+                    None => return Err("end without if ... begin".to_string()),
+                };
+                let here = self.here;
+                self.point_jump(jump, here)?;
+            }
+            "loop" => self.loops.push(Loop { start: self.here, exits: Vec::new() }),
+            "while" =>
+            {
+                if self.loops.is_empty()
+                {
+                    return Err("while outside of a loop".to_string());
+                }
+                let condition = self.condition()?;
+                self.skip_unless(condition.negate())?;
+                let jump = self.placeholder_jump()?;
+                if let Some(current) = self.loops.last_mut()
+                {
+                    current.exits.push(jump);
+                }
+            }
+            "again" =>
+            {
+                let current = match self.loops.pop()
+                {
+                    Some(current) => current,
+                    None => return Err("again without loop".to_string()),
+                };
+                let jump = self.placeholder_jump()?;
+                self.point_jump(jump, current.start)?;
+                let here = self.here;
+                for exit in current.exits
+                {
+                    self.point_jump(exit, here)?;
+                }
+            }
+            _ if self.macros.contains_key(&token) => self.expand_macro(&token)?,
+            _ if token.starts_with(':') => return Err(format!("Unsupported directive {}", token)),
+            _ => match parse_number(&token).or_else(|| self.constants.get(&token).cloned())
+            {
+                // A number by itself is a byte of data
+                Some(value) => self.emit_byte(calc_to_byte(value)?)?,
+                // Anything else is the name of a subroutine to call
+                None =>
+                {
+                    self.tokens.push_front(Token { text: token, line: self.line });
+                    self.address_instruction(0x2000)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn register_statement(&mut self, x: u8) -> Fallible<()>
+    {
+        let operator = self.next()?;
+        match operator.as_str()
+        {
+            ":=" => match self.peek()
+            {
+                Some("key") => { self.next()?; self.xy(0xF00A, x, 0) }
+                Some("delay") => { self.next()?; self.xy(0xF007, x, 0) }
+                Some("random") =>
+                {
+                    self.next()?;
+                    let mask = self.byte()?;
+                    self.xy(0xC000 | mask as u16, x, 0)
+                }
+                _ => match self.operand()?
+                {
+                    Operand::Register(y) => self.xy(0x8000, x, y),
+                    Operand::Byte(kk) => self.xy(0x6000 | kk as u16, x, 0),
+                },
+            },
+            "+=" => match self.operand()?
+            {
+                Operand::Register(y) => self.xy(0x8004, x, y),
+                Operand::Byte(kk) => self.xy(0x7000 | kk as u16, x, 0),
+            },
+            "-=" => match self.operand()?
+            {
+                Operand::Register(y) => self.xy(0x8005, x, y),
+                Operand::Byte(kk) => self.xy(0x7000 | kk.wrapping_neg() as u16, x, 0),
+            },
+            "|=" | "&=" | "^=" | "=-" | ">>=" | "<<=" =>
+            {
+                let y = self.register()?;
+                let low = match operator.as_str()
+                {
+                    "|=" => 0x1,
+                    "&=" => 0x2,
+                    "^=" => 0x3,
+                    "=-" => 0x7,
+                    ">>=" => 0x6,
+                    _ => 0xE,
+                };
+                self.xy(0x8000 | low, x, y)
+            }
+            _ => Err(format!("Unknown operator {} for a register", operator)),
+        }
+    }
+
+    fn index_statement(&mut self) -> Fallible<()>
+    {
+        match self.next()?.as_str()
+        {
+            ":=" => match self.peek()
+            {
+                Some("hex") => { self.next()?; let x = self.register()?; self.xy(0xF029, x, 0) }
+                Some("bighex") => { self.next()?; let x = self.register()?; self.xy(0xF030, x, 0) }
+                Some("long") =>
+                {
+                    self.next()?;
+                    self.emit(0xF000)?;
+                    let at = self.here;
+                    self.emit(0)?;
+                    self.address(at, FixupKind::Long)
+                }
+                _ => self.address_instruction(0xA000),
+            },
+            "+=" => { let x = self.register()?; self.xy(0xF01E, x, 0) }
+            other => Err(format!("Unknown operator {} for i", other)),
+        }
+    }
+
+    fn condition(&mut self) -> Fallible<Condition>
+    {
+        let register = self.register()?;
+        let token = self.next()?;
+        let comparison = match token.as_str()
+        {
+            "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            "<" => Comparison::Less,
+            ">" => Comparison::Greater,
+            "<=" => Comparison::LessEqual,
+            ">=" => Comparison::GreaterEqual,
+            "key" => Comparison::Key,
+            "-key" => Comparison::NotKey,
+            _ => return Err(format!("Unknown comparison {}", token)),
+        };
+        let operand = match comparison
+        {
+            Comparison::Key | Comparison::NotKey => Operand::Byte(0),
+            _ => self.operand()?,
+        };
+        Ok(Condition { register, comparison, operand })
+    }
+
+    // Emits the instructions that skip the next one when the condition is false.
+    // The ordering comparisons are done subtracting in vf, whose flag tells which
+    // operand is bigger
+    fn skip_unless(&mut self, condition: Condition) -> Fallible<()>
+    {
+        let x = condition.register;
+        match (condition.comparison, condition.operand)
+        {
+            (Comparison::Equal, Operand::Byte(kk)) => self.xy(0x4000 | kk as u16, x, 0),
+            (Comparison::NotEqual, Operand::Byte(kk)) => self.xy(0x3000 | kk as u16, x, 0),
+            (Comparison::Equal, Operand::Register(y)) => self.xy(0x9000, x, y),
+            (Comparison::NotEqual, Operand::Register(y)) => self.xy(0x5000, x, y),
+            (Comparison::Key, _) => self.xy(0xE0A1, x, 0),
+            (Comparison::NotKey, _) => self.xy(0xE09E, x, 0),
+            (comparison, operand) =>
+            {
+                // vf := operand
+                match operand
+                {
+                    Operand::Register(y) => self.xy(0x8F00, 0, y)?,
+                    Operand::Byte(kk) => self.emit(0x6F00 | kk as u16)?,
+                }
+                match comparison
+                {
+                    // vf -= vx: flag set when operand >= vx
+                    Comparison::Greater | Comparison::LessEqual => self.xy(0x8F05, 0, x)?,
+                    // vf =- vx: flag set when vx >= operand
+                    _ => self.xy(0x8F07, 0, x)?,
+                }
+                match comparison
+                {
+                    // The flag is set when the condition is false
+                    Comparison::Greater | Comparison::Less => self.emit(0x3F01),
+                    _ => self.emit(0x3F00),
+                }
+            }
+        }
+    }
+
+    // Tokens between braces, which can be nested
+    fn braces(&mut self) -> Fallible<Vec<Token>>
+    {
+        self.expect("{")?;
+        let mut depth = 1;
+        let mut tokens = Vec::new();
+        loop
+        {
+            let token = match self.tokens.pop_front()
+            {
+                Some(token) => token,
+                None => return Err("Missing }".to_string()),
+            };
+            match token.text.as_str()
+            {
+                "{" => depth += 1,
+                "}" =>
+                {
+                    depth -= 1;
+                    if depth == 0
+                    {
+                        return Ok(tokens);
+                    }
+                }
+                _ => {}
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn define_macro(&mut self) -> Fallible<()>
+    {
+        let name = self.name()?;
+        let mut arguments = Vec::new();
+        while self.peek().is_some_and(|t| t != "{")
+        {
+            arguments.push(self.next()?);
+        }
+        let body = self.braces()?;
+        self.macros.insert(name, Macro { arguments, body, calls: 0 });
+        Ok(())
+    }
+
+    // Replaces the macro and its arguments by its body
+    fn expand_macro(&mut self, name: &str) -> Fallible<()>
+    {
+        let mut definition = self.macros[name].clone();
+        let mut values = HashMap::new();
+        for argument in definition.arguments.iter()
+        {
+            values.insert(argument.clone(), self.next()?);
+        }
+        let line = self.line;
+        let calls = definition.calls.to_string();
+        for token in definition.body.iter().rev()
+        {
+            let text = match token.text.as_str()
+            {
+                "CALLS" => calls.clone(),
+                text => values.get(text).cloned().unwrap_or_else(|| text.to_string()),
+            };
+            // Errors inside the macro are reported at the line that uses it
+            self.tokens.push_front(Token { text, line });
+        }
+        definition.calls += 1;
+        if let Some(stored) = self.macros.get_mut(name)
+        {
+            stored.calls = definition.calls;
+        }
+        Ok(())
+    }
+
+    // Evaluates a :calc expression. Like in Octo there is no operator precedence,
+    // expressions are evaluated from right to left unless there are parentheses
+    fn calc(&self, tokens: &[Token]) -> Fallible<f64>
+    {
+        let mut position = 0;
+        let value = self.calc_expression(tokens, &mut position)?;
+        if position < tokens.len()
+        {
+            return Err(format!("Unexpected {} in expression", tokens[position].text));
+        }
+        Ok(value)
+    }
+
+    fn calc_expression(&self, tokens: &[Token], position: &mut usize) -> Fallible<f64>
+    {
+        let left = self.calc_term(tokens, position)?;
+        let operator = match tokens.get(*position)
+        {
+            Some(token) if token.text != ")" => token.text.clone(),
+            _ => return Ok(left),
+        };
+        *position += 1;
+        let right = self.calc_expression(tokens, position)?;
+        let (a, b) = (left as i64, right as i64);
+        let value = match operator.as_str()
+        {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => (a << b) as f64,
+            ">>" => (a >> b) as f64,
+            "<" => (left < right) as i64 as f64,
+            ">" => (left > right) as i64 as f64,
+            "<=" => (left <= right) as i64 as f64,
+            ">=" => (left >= right) as i64 as f64,
+            "==" => (left == right) as i64 as f64,
+            "!=" => (left != right) as i64 as f64,
+            _ => return Err(format!("Unknown operator {} in expression", operator)),
+        };
+        Ok(value)
+    }
+
+    fn calc_term(&self, tokens: &[Token], position: &mut usize) -> Fallible<f64>
+    {
+        let token = match tokens.get(*position)
+        {
+            Some(token) => token.text.as_str(),
+            None => return Err("Incomplete expression".to_string()),
+        };
+        *position += 1;
+        if token == "("
+        {
+            let value = self.calc_expression(tokens, position)?;
+            match tokens.get(*position)
+            {
+                Some(close) if close.text == ")" => *position += 1,
+                _ => return Err("Missing ) in expression".to_string()),
+            }
+            return Ok(value);
+        }
+        let unary: Option<fn(f64) -> f64> = match token
+        {
+            "-" => Some(|v| -v),
+            "~" => Some(|v| !(v as i64) as f64),
+            "!" => Some(|v| (v == 0.0) as i64 as f64),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(function) = unary
+        {
+            return Ok(function(self.calc_term(tokens, position)?));
+        }
+        if token == "@"
+        {
+            // Byte already assembled at an address
+            let address = self.calc_term(tokens, position)? as usize;
+            return Ok(address.checked_sub(PROGRAM_START)
+                        .and_then(|offset| self.rom.get(offset))
+                        .cloned().unwrap_or(0) as f64);
+        }
+        match token
+        {
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            _ => self.value_of(token)
+                    .or_else(|| token.parse().ok())
+                    .ok_or_else(|| format!("Unknown value {} in expression", token)),
+        }
+    }
+}
+
+fn calc_to_byte(value: f64) -> Fallible<u8>
+{
+    let value = value.floor() as i64;
+    if (-0x80..=0xFF).contains(&value)
+    {
+        Ok((value & 0xFF) as u8)
+    }
+    else
+    {
+        Err(format!("Value {} doesn't fit in a byte", value))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::assemble;
+    use super::super::{Chip8, Quirks, Variant};
+
+    // Runs a program with v0 = value and v1 = 5, telling whether the body of the if ran
+    fn condition_holds(value: u8, condition: &str) -> bool
+    {
+        let source = format!(": main\n v0 := {}\n v1 := 5\n v2 := 0\n if {} then v2 := 1\n : end\n jump end",
+                                value, condition);
+        let rom = assemble(&source).unwrap();
+        let mut chip = Chip8::new(Some(&rom), Variant::Chip8, Quirks::default()).unwrap();
+        for _ in 0..16
+        {
+            chip.step().unwrap();
+        }
+        chip.v_registers[2] == 1
+    }
+
+    #[test]
+    fn ordering_comparisons()
+    {
+        for &value in [0, 4, 5, 6, 0xFF].iter()
+        {
+            for operand in ["5", "v1"].iter()
+            {
+                let holds = |comparison| condition_holds(value, &format!("v0 {} {}", comparison, operand));
+                assert_eq!(holds("<"), value < 5, "v0 = {} < {}", value, operand);
+                assert_eq!(holds(">"), value > 5, "v0 = {} > {}", value, operand);
+                assert_eq!(holds("<="), value <= 5, "v0 = {} <= {}", value, operand);
+                assert_eq!(holds(">="), value >= 5, "v0 = {} >= {}", value, operand);
+            }
+        }
+    }
+
+    // Registers after running the program for `steps` instructions
+    fn run(source: &str, steps: usize) -> [u8; 0x10]
+    {
+        let rom = assemble(source).unwrap();
+        let mut chip = Chip8::new(Some(&rom), Variant::Chip8, Quirks::default()).unwrap();
+        for _ in 0..steps
+        {
+            chip.step().unwrap();
+        }
+        chip.v_registers
+    }
+
+    #[test]
+    fn labels()
+    {
+        // A jump to main is only added when the program doesn't start with it
+        let source = ": sub\n v0 := 1\n return\n: main\n sub\n jump main";
+        assert_eq!(assemble(source).unwrap(), [0x12, 0x06, 0x60, 0x01, 0x00, 0xEE, 0x22, 0x02, 0x12, 0x06]);
+        // Labels used before being defined are filled in at the end
+        let source = ": main\n jump end\n v0 := 1\n: end\n v1 := 2";
+        assert_eq!(assemble(source).unwrap(), [0x12, 0x04, 0x60, 0x01, 0x61, 0x02]);
+        assert!(assemble(": main\n jump nowhere").is_err());
+        assert!(assemble(": main\n: main").is_err());
+    }
+
+    #[test]
+    fn constants()
+    {
+        let source = ":const SPEED 3\n: main\n v0 := SPEED\n v0 += SPEED\n i := sprite\n: sprite\n SPEED";
+        assert_eq!(assemble(source).unwrap(), [0x60, 0x03, 0x70, 0x03, 0xA2, 0x06, 0x03]);
+        assert!(assemble(":const SPEED NOPE\n: main").is_err());
+    }
+
+    #[test]
+    fn loops()
+    {
+        let source = ": main\n v0 := 0\n loop\n v0 += 1\n while v0 != 5\n again\n v1 := v0\n: end\n jump end";
+        assert_eq!(assemble(source).unwrap()[..10], [0x60, 0x00, 0x70, 0x01, 0x40, 0x05, 0x12, 0x0A, 0x12, 0x02]);
+        let v = run(source, 30);
+        assert_eq!((v[0], v[1]), (5, 5));
+        assert!(assemble(": main\n again").is_err());
+        assert!(assemble(": main\n while v0 == 1").is_err());
+    }
+
+    #[test]
+    fn if_then_and_begin_else_end()
+    {
+        let source = |value| format!(": main\n v0 := {}\n if v0 == 3 then v1 := 1\n \
+                                      if v0 != 3 begin v2 := 1 else v2 := 2 end\n: end\n jump end", value);
+        let v = run(&source(3), 10);
+        assert_eq!((v[1], v[2]), (1, 2));
+        let v = run(&source(4), 10);
+        assert_eq!((v[1], v[2]), (0, 1));
+        assert!(assemble(": main\n if v0 == 3 v1 := 1").is_err());
+        assert!(assemble(": main\n end").is_err());
+    }
+}
@@ -0,0 +1,128 @@
+// Behaviours of the opcodes that differ between CHIP-8 implementations, the default
+// (every quirk off) is how this interpreter always ran them. The flag of 8xy5/8xy7
+// isn't a quirk, it's set when Vx equals Vy like everywhere else
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct Quirks
+{
+    // 8xy6/8xyE: shift Vy into Vx instead of shifting Vx in place
+    pub shift_vy: bool,
+    // Fx55/Fx65: leave I pointing after the last register stored or loaded (I += x + 1)
+    pub load_store_increment: bool,
+    // Fx55/Fx65: with load_store_increment, leave I pointing at the last register
+    // instead (I += x), a bug of CHIP-48
+    pub load_store_increment_x: bool,
+    // Bnnn: jump to nnn + Vx (x being the highest nibble of nnn) instead of nnn + V0
+    pub jump_vx: bool,
+    // 8xy1/8xy2/8xy3: set VF to 0 after the operation
+    pub vf_reset: bool,
+    // Dxyn: clip sprites at the edges of the screen instead of wrapping them around
+    pub clip_sprites: bool,
+    // Dxyn: wait for the next 60Hz frame after drawing a sprite
+    pub display_wait: bool,
+}
+
+pub const PRESET_NAMES: [&str; 5] = ["default", "vip", "chip48", "schip", "xochip"];
+
+impl Quirks
+{
+    // The COSMAC VIP interpreter
+    pub fn cosmac_vip() -> Quirks
+    {
+        Quirks
+        {
+            shift_vy: true,
+            load_store_increment: true,
+            load_store_increment_x: false,
+            jump_vx: false,
+            vf_reset: true,
+            clip_sprites: true,
+            display_wait: true,
+        }
+    }
+
+    // CHIP-48 on the HP-48, SUPER-CHIP without its fix of Fx55/Fx65
+    pub fn chip48() -> Quirks
+    {
+        Quirks
+        {
+            shift_vy: false,
+            load_store_increment: true,
+            load_store_increment_x: true,
+            jump_vx: true,
+            vf_reset: false,
+            clip_sprites: true,
+            display_wait: false,
+        }
+    }
+
+    // SUPER-CHIP 1.1
+    pub fn superchip() -> Quirks
+    {
+        Quirks
+        {
+            shift_vy: false,
+            load_store_increment: false,
+            load_store_increment_x: false,
+            jump_vx: true,
+            vf_reset: false,
+            clip_sprites: true,
+            display_wait: false,
+        }
+    }
+
+    // XO-CHIP as implemented by Octo
+    pub fn xochip() -> Quirks
+    {
+        Quirks
+        {
+            shift_vy: true,
+            load_store_increment: true,
+            load_store_increment_x: false,
+            jump_vx: false,
+            vf_reset: false,
+            clip_sprites: false,
+            display_wait: false,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Quirks>
+    {
+        match name.to_lowercase().as_str()
+        {
+            "default" => Some(Quirks::default()),
+            "vip" | "cosmac" | "cosmac-vip" => Some(Quirks::cosmac_vip()),
+            "chip48" | "chip-48" => Some(Quirks::chip48()),
+            "schip" | "superchip" | "super-chip" => Some(Quirks::superchip()),
+            "xochip" | "xo-chip" => Some(Quirks::xochip()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::Quirks;
+    use super::super::{Chip8, Variant};
+
+    // I after LD I, 0x300 and LD [I], V3 then LD V3, [I]
+    fn index_after_load_store(quirks: Quirks) -> usize
+    {
+        let rom = [0xA3, 0x00, 0xF3, 0x55, 0xF3, 0x65];
+        let mut chip = Chip8::new(Some(&rom), Variant::SuperChip, quirks).unwrap();
+        for _ in 0..3
+        {
+            chip.step().unwrap();
+        }
+        chip.index
+    }
+
+    #[test]
+    fn load_store_increment()
+    {
+        assert_eq!(index_after_load_store(Quirks::default()), 0x300);
+        assert_eq!(index_after_load_store(Quirks::superchip()), 0x300);
+        assert_eq!(index_after_load_store(Quirks::cosmac_vip()), 0x308);
+        assert_eq!(index_after_load_store(Quirks::chip48()), 0x306);
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::VecDeque;
+use super::{Chip8, Result};
+
+// Ring buffer of the last save states of the machine, one per frame.
+// Only the newest state is kept whole, every older one is stored as the difference
+// with the state after it: the xor of both, run length encoded since it's mostly 0
+pub struct Rewind
+{
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind
+{
+    // Buffer holding `frames` frames
+    pub fn new(frames: usize) -> Rewind
+    {
+        Rewind
+        {
+            capacity: frames,
+            latest: None,
+            deltas: VecDeque::with_capacity(frames),
+        }
+    }
+
+    // Buffer holding `seconds` seconds at 60 frames per second
+    pub fn with_seconds(seconds: usize) -> Rewind
+    {
+        Rewind::new(seconds * 60)
+    }
+
+    pub fn push(&mut self, chip: &Chip8)
+    {
+        let state = chip.save_state();
+        if let Some(latest) = self.latest.take()
+        {
+            if latest.len() == state.len()
+            {
+                self.deltas.push_back(encode_delta(&latest, &state));
+                if self.deltas.len() > self.capacity
+                {
+                    self.deltas.pop_front();
+                }
+            }
+            else
+            {
+                self.deltas.clear();
+            }
+        }
+        self.latest = Some(state);
+    }
+
+    // Takes the machine one frame back, returns false when there is nothing left
+    pub fn step_back(&mut self, chip: &mut Chip8) -> Result<bool>
+    {
+        let delta = match self.deltas.pop_back()
+        {
+            Some(delta) => delta,
+            None => return Ok(false),
+        };
+        let mut state = self.latest.take().unwrap_or_default();
+        apply_delta(&mut state, &delta);
+        chip.load_state(&state)?;
+        self.latest = Some(state);
+        Ok(true)
+    }
+}
+
+// Delta format: pairs of (number of unchanged bytes, number of changed bytes) as
+// varints, each pair followed by the xor of the changed bytes
+fn encode_delta(older: &[u8], newer: &[u8]) -> Vec<u8>
+{
+    let mut delta = Vec::new();
+    let mut i = 0;
+    while i < older.len()
+    {
+        let unchanged_start = i;
+        while i < older.len() && older[i] == newer[i]
+        {
+            i += 1;
+        }
+        let changed_start = i;
+        while i < older.len() && older[i] != newer[i]
+        {
+            i += 1;
+        }
+        write_varint(&mut delta, changed_start - unchanged_start);
+        write_varint(&mut delta, i - changed_start);
+        delta.extend(older[changed_start..i].iter().zip(&newer[changed_start..i]).map(|(a, b)| a ^ b));
+    }
+    delta
+}
+
+fn apply_delta(state: &mut [u8], delta: &[u8])
+{
+    let mut position = 0;
+    let mut i = 0;
+    while i < delta.len()
+    {
+        position += read_varint(delta, &mut i);
+        let changed = read_varint(delta, &mut i);
+        for (byte, xor) in state[position..position + changed].iter_mut().zip(&delta[i..i + changed])
+        {
+            *byte ^= xor;
+        }
+        position += changed;
+        i += changed;
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize)
+{
+    while value >= 0x80
+    {
+        out.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], i: &mut usize) -> usize
+{
+    let mut value = 0;
+    let mut shift = 0;
+    loop
+    {
+        let byte = data[*i];
+        *i += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0
+        {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::Rewind;
+    use super::super::{Chip8, Quirks, Variant};
+    use super::super::tests::run_frames;
+
+    #[test]
+    fn steps_back_through_every_frame()
+    {
+        let rom = include_bytes!("../../roms/BRIX");
+        let mut chip = Chip8::new(Some(rom), Variant::Chip8, Quirks::default()).unwrap();
+        let mut rewind = Rewind::new(30);
+        let mut states = Vec::new();
+        for _ in 0..40
+        {
+            run_frames(&mut chip, 1);
+            rewind.push(&chip);
+            states.push(chip.save_state());
+        }
+        // Only the 30 frames before the last one are kept
+        for (frame, state) in states.iter().enumerate().rev().skip(1).take(30)
+        {
+            assert!(rewind.step_back(&mut chip).unwrap());
+            assert_eq!(&chip.save_state(), state);
+            assert_eq!(chip.frame(), frame as u64 + 1);
+        }
+        assert!(!rewind.step_back(&mut chip).unwrap());
+    }
+}
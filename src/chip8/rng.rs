@@ -0,0 +1,190 @@
+// Source of the numbers of Cxkk. It belongs to the machine and is part of its save
+// states, so a run can be reproduced from its seed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RngKind
+{
+    // xorshift32, good quality numbers
+    Xorshift,
+    // The RND routine of the COSMAC VIP interpreter, which some old roms were tuned
+    // for. R9 is stepped every frame and by every RND, and its low byte picks a byte
+    // of the code of the interpreter that is added to the high byte
+    CosmacVip,
+}
+
+pub const RNG_NAMES: [&str; 2] = ["xorshift", "vip"];
+
+// Second page (0x100-0x1FF) of the COSMAC VIP CHIP-8 interpreter, read by its RND
+const VIP_INTERPRETER_PAGE: [u8; 0x100] =
+[
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x45, 0xA3, 0x98, 0x56, 0xD4, 0xF8, 0x81, 0xBC, 0xF8, 0x95, 0xAC,
+    0x22, 0xDC, 0x12, 0x56, 0xD4, 0x06, 0xB8, 0xD4, 0x06, 0xA8, 0xD4, 0x64, 0x0A, 0x01, 0xE6, 0x8A,
+    0xF4, 0xAA, 0x3B, 0x28, 0x9A, 0xFC, 0x01, 0xBA, 0xD4, 0xF8, 0x81, 0xBA, 0x06, 0xFA, 0x0F, 0xAA,
+    0x0A, 0xAA, 0xD4, 0xE6, 0x06, 0xBF, 0x93, 0xBE, 0xF8, 0x1B, 0xAE, 0x2A, 0x1A, 0xF8, 0x00, 0x5A,
+    0x0E, 0xF5, 0x3B, 0x4B, 0x56, 0x0A, 0xFC, 0x01, 0x5A, 0x30, 0x40, 0x4E, 0xF6, 0x3B, 0x3C, 0x9F,
+    0x56, 0x2A, 0x2A, 0xD4, 0x00, 0x22, 0x86, 0x52, 0xF8, 0xF0, 0xA7, 0x07, 0x5A, 0x87, 0xF3, 0x17,
+    0x1A, 0x3A, 0x5B, 0x12, 0xD4, 0x22, 0x86, 0x52, 0xF8, 0xF0, 0xA7, 0x0A, 0x57, 0x87, 0xF3, 0x17,
+    0x1A, 0x3A, 0x6B, 0x12, 0xD4, 0x15, 0x85, 0x22, 0x73, 0x95, 0x52, 0x25, 0x45, 0xA5, 0x86, 0xFA,
+    0x0F, 0xB5, 0xD4, 0x45, 0xE6, 0xF3, 0x3A, 0x82, 0x15, 0x15, 0xD4, 0x45, 0xE6, 0xF3, 0x3A, 0x88,
+    0xD4, 0x45, 0x07, 0x30, 0x8C, 0x45, 0x07, 0x30, 0x84, 0xE6, 0x62, 0x26, 0x45, 0xA3, 0x36, 0x88,
+    0xD4, 0x3E, 0x88, 0xD4, 0xF8, 0xF0, 0xA7, 0xE7, 0x45, 0xF4, 0xA5, 0x86, 0xFA, 0x0F, 0x3B, 0xB2,
+    0xFC, 0x01, 0xB5, 0xD4, 0x45, 0x56, 0xD4, 0x45, 0xE6, 0xF4, 0x56, 0xD4, 0x45, 0xFA, 0x0F, 0x3A,
+    0xC4, 0x07, 0x56, 0xD4, 0xAF, 0x22, 0xF8, 0xD3, 0x73, 0x8F, 0xF9, 0xF0, 0x52, 0xE6, 0x07, 0xD2,
+    0x56, 0xF8, 0xFF, 0xA6, 0xF8, 0x00, 0x7E, 0x56, 0xD4, 0x19, 0x89, 0xAE, 0x93, 0xBE, 0x99, 0xEE,
+    0xF4, 0x56, 0x76, 0xE6, 0xF4, 0xB9, 0x56, 0x45, 0xF2, 0x56, 0xD4, 0x45, 0xAA, 0x86, 0xFA, 0x0F,
+    0xBA, 0xD4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE0, 0x00, 0x4B,
+];
+
+impl RngKind
+{
+    pub fn from_name(name: &str) -> Option<RngKind>
+    {
+        match name.to_lowercase().as_str()
+        {
+            "xorshift" => Some(RngKind::Xorshift),
+            "vip" | "cosmac" | "cosmac-vip" => Some(RngKind::CosmacVip),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rng
+{
+    kind: RngKind,
+    // xorshift state, or R9 of the VIP
+    state: u32,
+}
+
+impl Rng
+{
+    pub fn new(kind: RngKind, seed: u32) -> Rng
+    {
+        let mut rng = Rng { kind, state: 0 };
+        rng.set_state(seed);
+        rng
+    }
+
+    pub fn state(&self) -> u32
+    {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: u32)
+    {
+        self.state = match self.kind
+        {
+            // xorshift gets stuck at 0
+            RngKind::Xorshift if state == 0 => 0x9E37_79B9,
+            RngKind::Xorshift => state,
+            RngKind::CosmacVip => state & 0xFFFF,
+        };
+    }
+
+    // Called every frame, like the interrupt routine of the VIP
+    pub fn tick(&mut self)
+    {
+        if self.kind == RngKind::CosmacVip
+        {
+            self.state = (self.state + 1) & 0xFFFF;
+        }
+    }
+
+    pub fn next_byte(&mut self) -> u8
+    {
+        match self.kind
+        {
+            RngKind::Xorshift =>
+            {
+                let mut x = self.state;
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                self.state = x;
+                (x >> 24) as u8
+            }
+            RngKind::CosmacVip =>
+            {
+                // INC R9, then the byte of the interpreter at R9.0 plus R9.1, shifted
+                // right with the carry of the addition and added to itself again
+                let r9 = (self.state + 1) & 0xFFFF;
+                let code = VIP_INTERPRETER_PAGE[(r9 & 0xFF) as usize];
+                let (sum, carry) = ((r9 >> 8) as u8).overflowing_add(code);
+                let result = ((carry as u8) << 7 | sum >> 1).wrapping_add(sum);
+                self.state = (result as u32) << 8 | (r9 & 0xFF);
+                result
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{Rng, RngKind};
+    use super::super::{Chip8, Quirks, Variant};
+
+    #[test]
+    fn vip_sequence()
+    {
+        let mut rng = Rng::new(RngKind::CosmacVip, 0x00D8);
+        let numbers: Vec<u8> = (0..5).map(|_| rng.next_byte()).collect();
+        assert_eq!(numbers, [0x25, 0x05, 0x0C, 0xEE, 0x82]);
+        assert_eq!(rng.state(), 0x82DD);
+        // A frame steps R9 too, carrying into the high byte
+        let mut rng = Rng::new(RngKind::CosmacVip, 0x12FE);
+        rng.tick();
+        assert_eq!(rng.state(), 0x12FF);
+        assert_eq!(rng.next_byte(), 0x1C);
+        assert_eq!(rng.state(), 0x1C00);
+    }
+
+    // V0 after each of the first `count` RND V0, 0xFF of a machine seeded with `seed`
+    fn random_numbers(kind: RngKind, seed: u32, count: usize) -> Vec<u8>
+    {
+        // RND V0, 0xFF ; JP 0x200
+        let rom = [0xC0, 0xFF, 0x12, 0x00];
+        let mut chip = Chip8::new(Some(&rom), Variant::Chip8, Quirks::default()).unwrap();
+        chip.set_rng(Rng::new(kind, seed));
+        (0..count).map(|_|
+        {
+            chip.step().unwrap();
+            chip.step().unwrap();
+            chip.tick_timers();
+            chip.v_registers[0]
+        }).collect()
+    }
+
+    #[test]
+    fn same_seed_same_numbers()
+    {
+        for &kind in [RngKind::Xorshift, RngKind::CosmacVip].iter()
+        {
+            assert_eq!(random_numbers(kind, 1234, 50), random_numbers(kind, 1234, 50));
+            assert_ne!(random_numbers(kind, 1234, 50), random_numbers(kind, 4321, 50));
+        }
+    }
+
+    #[test]
+    fn state_is_saved()
+    {
+        for &kind in [RngKind::Xorshift, RngKind::CosmacVip].iter()
+        {
+            let rom = [0xC0, 0xFF, 0x12, 0x00];
+            let mut chip = Chip8::new(Some(&rom), Variant::Chip8, Quirks::default()).unwrap();
+            chip.set_rng(Rng::new(kind, 99));
+            for _ in 0..10
+            {
+                chip.step().unwrap();
+            }
+            let mut copy = Chip8::new(None, Variant::Chip8, Quirks::default()).unwrap();
+            copy.set_rng(Rng::new(kind, 7));
+            copy.load_state(&chip.save_state()).unwrap();
+            for _ in 0..10
+            {
+                chip.step().unwrap();
+                copy.step().unwrap();
+                assert_eq!(chip.v_registers[0], copy.v_registers[0]);
+            }
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use super::{Chip8, Result};
+use super::frontend::{Frontend, Command};
+use super::state::SaveSlots;
+use super::rewind::Rewind;
+use super::debugger::Debugger;
+use super::fault::{Fault, FaultPolicies, FaultPolicy};
+use super::input::InputScript;
+use super::movie::{MoviePlayer, MovieRecorder};
+use super::scheduler::{Clock, Scheduler, SystemClock};
+use super::trace::{Tracer, TraceSnapshot};
+
+// Drives a chip with a frontend, handling the hotkeys of the emulator
+pub struct Runner
+{
+    pub save_slots: Option<SaveSlots>,
+    pub rewind: Option<Rewind>,
+    pub debugger: Option<Debugger>,
+    pub scheduler: Scheduler,
+    pub fault_policies: FaultPolicies,
+    // Replaces the keys of the frontend
+    pub input: Option<InputScript>,
+    // Replaces the keys and the speed of the frontend
+    pub player: Option<MoviePlayer>,
+    pub recorder: Option<MovieRecorder>,
+    pub tracer: Option<Tracer>,
+    // Stopped with the pause hotkey
+    paused: bool,
+    // Stopped by a fault with the halt policy
+    halted: Option<Fault>,
+    status: String,
+}
+
+impl Runner
+{
+    pub fn new() -> Runner
+    {
+        Runner
+        {
+            save_slots: None,
+            rewind: None,
+            debugger: None,
+            scheduler: Scheduler::default(),
+            fault_policies: FaultPolicies::default(),
+            input: None,
+            player: None,
+            recorder: None,
+            tracer: None,
+            paused: false,
+            halted: None,
+            status: String::new(),
+        }
+    }
+
+    // Returns true if the command took the machine back in time
+    fn handle_command(&mut self, chip: &mut Chip8, command: Command) -> bool
+    {
+        match command
+        {
+            Command::SpeedUp => self.scheduler.speed_up(),
+            Command::SlowDown => self.scheduler.slow_down(),
+            Command::TogglePause => self.paused = !self.paused,
+            _ => {}
+        }
+        let result = match (command, &self.save_slots, &mut self.rewind)
+        {
+            (Command::SaveState(slot), Some(slots), _) => slots.save(chip, slot).map(|_| false),
+            (Command::LoadState(slot), Some(slots), _) => slots.load(chip, slot).map(|_| true),
+            (Command::Rewind, _, Some(rewind)) => rewind.step_back(chip).map(|_| true),
+            _ => Ok(false),
+        };
+        match result
+        {
+            Ok(rewound) => rewound,
+            Err(e) =>
+            {
+                eprintln!("{}", e);
+                for e in e.iter().skip(1)
+                {
+                    eprintln!("caused by: {}", e);
+                }
+                false
+            }
+        }
+    }
+
+    // Executes an instruction unless the debugger stops it
+    fn step(&mut self, chip: &mut Chip8) -> ::std::result::Result<(), Fault>
+    {
+        if let Some(ref mut debugger) = self.debugger
+        {
+            if !debugger.before_step(chip)
+            {
+                return Ok(());
+            }
+        }
+        let before = self.tracer.as_ref().and_then(|_| TraceSnapshot::of(chip));
+        let result = chip.step();
+        if let (Some(tracer), Some(before)) = (self.tracer.as_mut(), before)
+        {
+            match result
+            {
+                Ok(()) => tracer.log(&before, chip),
+                Err(ref fault) => tracer.log_fault(fault),
+            }
+        }
+        result?;
+        if let Some(ref mut debugger) = self.debugger
+        {
+            debugger.after_step(chip);
+        }
+        Ok(())
+    }
+
+    // Sets the keys held during the frame about to run
+    fn update_keys<F: Frontend>(&mut self, chip: &mut Chip8, frontend: &F)
+    {
+        if let Some(ref mut player) = self.player
+        {
+            player.advance(chip.frame());
+            if let Some(cycles_per_frame) = player.cycles_per_frame()
+            {
+                self.scheduler.cycles_per_frame = cycles_per_frame;
+            }
+            chip.update_keys(player);
+        }
+        else if let Some(ref mut input) = self.input
+        {
+            input.advance(chip.frame());
+            chip.update_keys(input);
+        }
+        else
+        {
+            chip.update_keys(frontend);
+        }
+        if let Some(ref mut recorder) = self.recorder
+        {
+            recorder.record_frame(chip.frame(), chip.keys_down(), self.scheduler.cycles_per_frame);
+        }
+    }
+
+    // Applies the policy of the kind of fault, wrapping is done by the machine
+    fn handle_fault(&mut self, chip: &mut Chip8, fault: Fault)
+    {
+        match self.fault_policies.policy(fault.kind)
+        {
+            FaultPolicy::Skip =>
+            {
+                eprintln!("{}, skipped", fault);
+                chip.skip_instruction();
+            }
+            FaultPolicy::Debug => self.debugger.get_or_insert_with(Debugger::new).stop_at_fault(chip, &fault),
+            FaultPolicy::Halt | FaultPolicy::Wrap => self.halted = Some(fault),
+        }
+    }
+
+    fn is_paused(&self) -> bool
+    {
+        self.paused || self.halted.is_some() || self.debugger.as_ref().is_some_and(Debugger::is_paused)
+    }
+
+    // Shows the speed in the frontend when it changes
+    fn update_status<F: Frontend>(&mut self, frontend: &mut F)
+    {
+        let mut status = format!("{} IPS", self.scheduler.instructions_per_second());
+        if self.halted.is_some()
+        {
+            status.push_str(", halted");
+        }
+        else if self.paused
+        {
+            status.push_str(", paused");
+        }
+        else if self.scheduler.fast_forward
+        {
+            status.push_str(", fast forward");
+        }
+        else if self.scheduler.slow_motion
+        {
+            status.push_str(", slow motion");
+        }
+        if status != self.status
+        {
+            frontend.set_status(&status);
+            self.status = status;
+        }
+    }
+
+    // Runs the chip until the display is closed or the program exits
+    pub fn run<F: Frontend>(&mut self, chip: &mut Chip8, frontend: &mut F) -> Result<()>
+    {
+        self.run_with_clock(chip, frontend, &mut SystemClock::new())
+    }
+
+    // Same as run, with the time given by `clock`
+    pub fn run_with_clock<F: Frontend, C: Clock>(&mut self, chip: &mut Chip8, frontend: &mut F,
+                                                 clock: &mut C) -> Result<()>
+    {
+        let result = self.run_frames(chip, frontend, clock);
+        // The trace is most needed when the run failed, so it's written out either way
+        let traced = self.tracer.as_mut().map_or(Ok(()), Tracer::finish);
+        result.and(traced)?;
+        match self.halted
+        {
+            Some(fault) => Err(fault.into()),
+            None => Ok(()),
+        }
+    }
+
+    fn run_frames<F: Frontend, C: Clock>(&mut self, chip: &mut Chip8, frontend: &mut F,
+                                         clock: &mut C) -> Result<()>
+    {
+        self.scheduler.start(clock);
+        chip.set_wrapping(self.fault_policies.memory == FaultPolicy::Wrap,
+                          self.fault_policies.pc == FaultPolicy::Wrap);
+        while frontend.is_open() && !chip.has_exited()
+        {
+            if let Some(ref mut debugger) = self.debugger
+            {
+                debugger.poll(chip);
+            }
+
+            // The machine is frozen while rewinding
+            let mut rewinding = false;
+            let commands = frontend.commands();
+            self.scheduler.fast_forward = commands.contains(&Command::FastForward);
+            self.scheduler.slow_motion = commands.contains(&Command::SlowMotion);
+            for command in commands
+            {
+                rewinding |= self.handle_command(chip, command);
+            }
+            self.update_status(frontend);
+            if !rewinding && !self.is_paused()
+            {
+                self.update_keys(chip, frontend);
+                for _ in 0..self.scheduler.cycles_per_frame
+                {
+                    if let Err(fault) = self.step(chip)
+                    {
+                        self.handle_fault(chip, fault);
+                    }
+                    if self.is_paused() || chip.has_exited()
+                    {
+                        break;
+                    }
+                }
+                // Time stands still while the debugger is stopped
+                if !self.is_paused()
+                {
+                    chip.tick_timers();
+                    if let Some(ref mut recorder) = self.recorder
+                    {
+                        recorder.record_state(chip.frame(), chip);
+                    }
+                    if let Some(ref player) = self.player
+                    {
+                        player.check_state(chip.frame(), chip)?;
+                    }
+                    if let Some(ref mut rewind) = self.rewind
+                    {
+                        rewind.push(chip);
+                    }
+                }
+            }
+
+            if let Some((pattern, rate)) = chip.audio_pattern()
+            {
+                frontend.set_pattern(pattern, rate);
+            }
+            frontend.set_buzzing(chip.is_buzzing());
+            if chip.take_draw()
+            {
+                frontend.draw(chip.screen())?;
+            }
+            else
+            {
+                frontend.refresh()?;
+            }
+            self.scheduler.wait_next_frame(clock);
+        }
+        Ok(())
+    }
+}
+
+impl Default for Runner
+{
+    fn default() -> Runner
+    {
+        Runner::new()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::sync::mpsc;
+    use super::Runner;
+    use super::super::{Chip8, Quirks, Result, Variant};
+    use super::super::debugger::Debugger;
+    use super::super::fault::{FaultKind, FaultPolicies, FaultPolicy};
+    use super::super::frontend::{Composite, NullBuzzer, NullKeypad};
+    use super::super::headless::HeadlessDisplay;
+    use super::super::scheduler::VirtualClock;
+
+    // LD V0, 1 ; an invalid opcode ; LD V1, 2 ; JP 0x206
+    const ROM: [u8; 8] = [0x60, 0x01, 0x51, 0x21, 0x61, 0x02, 0x12, 0x06];
+
+    fn run(runner: &mut Runner, rom: &[u8]) -> (Chip8, Result<()>)
+    {
+        let mut chip = Chip8::new(Some(rom), Variant::Chip8, Quirks::default()).unwrap();
+        let mut frontend = Composite { display: HeadlessDisplay::new(3), keypad: NullKeypad, buzzer: NullBuzzer };
+        let result = runner.run_with_clock(&mut chip, &mut frontend, &mut VirtualClock::new());
+        (chip, result)
+    }
+
+    #[test]
+    fn halt_stops_at_the_fault()
+    {
+        let mut runner = Runner::new();
+        let (chip, result) = run(&mut runner, &ROM);
+        assert_eq!(result.unwrap_err().to_string(), "Invalid opcode executing instruction 0x5121 at 0x0202");
+        assert_eq!(runner.halted.map(|fault| fault.kind), Some(FaultKind::InvalidOpcode));
+        assert_eq!((chip.program_counter, chip.v_registers[0], chip.v_registers[1]), (0x202, 1, 0));
+    }
+
+    #[test]
+    fn skip_goes_on_after_the_fault()
+    {
+        let mut runner = Runner::new();
+        runner.fault_policies = FaultPolicies::parse("skip").unwrap();
+        let (chip, result) = run(&mut runner, &ROM);
+        assert!(result.is_ok());
+        assert!(runner.halted.is_none());
+        assert_eq!((chip.program_counter, chip.v_registers[1]), (0x206, 2));
+    }
+
+    #[test]
+    fn debug_stops_in_the_debugger()
+    {
+        let mut runner = Runner::new();
+        runner.fault_policies = FaultPolicies::parse("debug").unwrap();
+        // The debugger starts stopped
+        let (sender, receiver) = mpsc::channel();
+        runner.debugger = Some(Debugger::with_input(receiver));
+        sender.send("c".to_string()).unwrap();
+        let (chip, result) = run(&mut runner, &ROM);
+        assert!(result.is_ok());
+        assert!(runner.halted.is_none());
+        assert!(runner.debugger.as_ref().unwrap().is_paused());
+        assert_eq!((chip.program_counter, chip.v_registers[1]), (0x202, 0));
+    }
+
+    #[test]
+    fn wrap_goes_around_the_memory()
+    {
+        // LD V0, 0xAA ; LD V1, 0xBB ; LD I, 0xFFF ; LD [I], V1 ; JP 0x208
+        let rom = [0x60, 0xAA, 0x61, 0xBB, 0xAF, 0xFF, 0xF1, 0x55, 0x12, 0x08];
+        let mut runner = Runner::new();
+        runner.fault_policies = FaultPolicies::parse("wrap").unwrap();
+        let (chip, result) = run(&mut runner, &rom);
+        assert!(result.is_ok());
+        assert_eq!(chip.program_counter, 0x208);
+        // V1 went to address 0
+        assert_eq!((chip.mem[0xFFF], chip.mem[0]), (0xAA, 0xBB));
+        // Faults that can't wrap halt
+        let mut runner = Runner::new();
+        runner.fault_policies = FaultPolicies::all(FaultPolicy::Wrap);
+        let (chip, result) = run(&mut runner, &ROM);
+        assert!(result.is_err());
+        assert!(runner.halted.is_some());
+        assert_eq!(chip.program_counter, 0x202);
+    }
+}
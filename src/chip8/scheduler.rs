@@ -0,0 +1,229 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Instructions per frame by default, about 1000 per second
+pub const DEFAULT_CYCLES_PER_FRAME: usize = 16;
+pub const MAX_CYCLES_PER_FRAME: usize = 10000;
+pub const FRAMES_PER_SECOND: u32 = 60;
+// Slow motion makes every frame last this many frames
+const SLOW_MOTION_FACTOR: u32 = 4;
+// When the machine is this late it gives up catching up instead of running frames
+// back to back, like after the window was dragged
+const MAX_LAG: Duration = Duration::from_millis(250);
+
+pub fn frame_duration() -> Duration
+{
+    Duration::from_secs(1) / FRAMES_PER_SECOND
+}
+
+// Time source of the scheduler
+pub trait Clock
+{
+    // Time elapsed since the clock was created
+    fn now(&self) -> Duration;
+    fn sleep(&mut self, duration: Duration);
+}
+
+// Wall clock time
+pub struct SystemClock
+{
+    start: Instant,
+}
+
+impl SystemClock
+{
+    pub fn new() -> SystemClock
+    {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Default for SystemClock
+{
+    fn default() -> SystemClock
+    {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock
+{
+    fn now(&self) -> Duration
+    {
+        self.start.elapsed()
+    }
+
+    fn sleep(&mut self, duration: Duration)
+    {
+        thread::sleep(duration);
+    }
+}
+
+// Clock whose time only moves when slept on, runs as fast as possible and always
+// the same way, for tests and headless runs
+#[derive(Default)]
+pub struct VirtualClock
+{
+    now: Duration,
+}
+
+impl VirtualClock
+{
+    pub fn new() -> VirtualClock
+    {
+        VirtualClock { now: Duration::from_secs(0) }
+    }
+}
+
+impl Clock for VirtualClock
+{
+    fn now(&self) -> Duration
+    {
+        self.now
+    }
+
+    fn sleep(&mut self, duration: Duration)
+    {
+        self.now += duration;
+    }
+}
+
+// Paces the machine at 60 frames per second, each frame running cycles_per_frame
+// instructions and one tick of the timers
+pub struct Scheduler
+{
+    pub cycles_per_frame: usize,
+    // Runs frames as fast as possible
+    pub fast_forward: bool,
+    pub slow_motion: bool,
+    // Time the next frame starts at
+    next_frame: Duration,
+}
+
+impl Scheduler
+{
+    pub fn new(cycles_per_frame: usize) -> Scheduler
+    {
+        Scheduler
+        {
+            cycles_per_frame: cycles_per_frame.clamp(1, MAX_CYCLES_PER_FRAME),
+            fast_forward: false,
+            slow_motion: false,
+            next_frame: Duration::from_secs(0),
+        }
+    }
+
+    // Cycles per frame giving about `ips` instructions per second
+    pub fn with_ips(ips: usize) -> Scheduler
+    {
+        Scheduler::new((ips + FRAMES_PER_SECOND as usize / 2) / FRAMES_PER_SECOND as usize)
+    }
+
+    pub fn instructions_per_second(&self) -> usize
+    {
+        self.cycles_per_frame * FRAMES_PER_SECOND as usize
+    }
+
+    // Steps of about 25%, at least one cycle
+    pub fn speed_up(&mut self)
+    {
+        self.cycles_per_frame = (self.cycles_per_frame * 5 / 4).max(self.cycles_per_frame + 1)
+                                    .min(MAX_CYCLES_PER_FRAME);
+    }
+
+    pub fn slow_down(&mut self)
+    {
+        self.cycles_per_frame = (self.cycles_per_frame * 4 / 5).min(self.cycles_per_frame - 1).max(1);
+    }
+
+    // Starts counting frames from now
+    pub fn start<C: Clock>(&mut self, clock: &C)
+    {
+        self.next_frame = clock.now();
+    }
+
+    // Sleeps until the next frame is due
+    pub fn wait_next_frame<C: Clock>(&mut self, clock: &mut C)
+    {
+        let now = clock.now();
+        if self.fast_forward
+        {
+            self.next_frame = now;
+            return;
+        }
+        self.next_frame += if self.slow_motion { frame_duration() * SLOW_MOTION_FACTOR } else { frame_duration() };
+        if now < self.next_frame
+        {
+            clock.sleep(self.next_frame - now);
+        }
+        else if now - self.next_frame > MAX_LAG
+        {
+            self.next_frame = now;
+        }
+    }
+}
+
+impl Default for Scheduler
+{
+    fn default() -> Scheduler
+    {
+        Scheduler::new(DEFAULT_CYCLES_PER_FRAME)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::time::Duration;
+    use super::{frame_duration, Clock, Scheduler, VirtualClock};
+    use super::super::{Chip8, Quirks, Variant};
+    use super::super::frontend::{Composite, NullBuzzer, NullKeypad};
+    use super::super::headless::HeadlessDisplay;
+    use super::super::runner::Runner;
+
+    #[test]
+    fn frames_on_virtual_clock()
+    {
+        // LD V0, 0xFF; LD DT, V0; loop: ADD V1, 1; JP loop
+        let rom = [0x60, 0xFF, 0xF0, 0x15, 0x71, 0x01, 0x12, 0x04];
+        let mut chip = Chip8::new(Some(&rom), Variant::Chip8, Quirks::default()).unwrap();
+        let mut runner = Runner::new();
+        runner.scheduler = Scheduler::new(16);
+        let mut frontend = Composite { display: HeadlessDisplay::new(10), keypad: NullKeypad, buzzer: NullBuzzer };
+        let mut clock = VirtualClock::new();
+        runner.run_with_clock(&mut chip, &mut frontend, &mut clock).unwrap();
+        // 16 instructions in each of the 10 frames, after the first 2 every other one is an ADD
+        assert_eq!(chip.v_registers[1], 79);
+        // One tick of the timers per frame
+        assert_eq!(chip.dt, 0xFF - 10);
+        assert_eq!(clock.now(), frame_duration() * 10);
+    }
+
+    #[test]
+    fn fast_forward_and_slow_motion()
+    {
+        let mut clock = VirtualClock::new();
+        let mut scheduler = Scheduler::default();
+        scheduler.start(&clock);
+        scheduler.fast_forward = true;
+        scheduler.wait_next_frame(&mut clock);
+        assert_eq!(clock.now(), Duration::from_secs(0));
+        scheduler.fast_forward = false;
+        scheduler.slow_motion = true;
+        scheduler.wait_next_frame(&mut clock);
+        assert_eq!(clock.now(), frame_duration() * 4);
+    }
+
+    #[test]
+    fn speed()
+    {
+        assert_eq!(Scheduler::with_ips(1000).cycles_per_frame, 17);
+        let mut scheduler = Scheduler::new(1);
+        scheduler.slow_down();
+        assert_eq!(scheduler.cycles_per_frame, 1);
+        scheduler.speed_up();
+        assert_eq!(scheduler.cycles_per_frame, 2);
+        scheduler.speed_up();
+        assert_eq!(scheduler.instructions_per_second(), 3 * 60);
+    }
+}
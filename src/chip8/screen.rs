@@ -0,0 +1,250 @@
+use super::{Result, C8_SCREEN_WIDTH, C8_SCREEN_HEIGTH};
+use super::state::{StateReader, StateWriter};
+
+pub const SCHIP_SCREEN_WIDTH: usize = 128;
+pub const SCHIP_SCREEN_HEIGTH: usize = 64;
+
+// Colours (0xAARRGGBB) of the pixels by the planes they are set in, only XO-CHIP
+// uses the last two
+pub const PALETTE: [u32; 4] = [0x00000000, 0xFFFFFFFF, 0xFF808080, 0xFF404040];
+
+// Display of the machine, 64*32 in low resolution and 128*64 in the SUPER-CHIP high
+// resolution mode. Low resolution only uses the top left corner of the pixels.
+// Every pixel holds one bit per bitplane, CHIP-8 and SUPER-CHIP only use the first
+// plane while XO-CHIP can draw on two, giving 4 colours
+#[derive(Clone)]
+pub struct Screen
+{
+    hires: bool,
+    planes: u8, // bitmask of the planes affected by drawing, clearing and scrolling
+    pixels: [[u8; SCHIP_SCREEN_WIDTH]; SCHIP_SCREEN_HEIGTH],
+}
+
+impl Screen
+{
+    pub fn new() -> Screen
+    {
+        Screen
+        {
+            hires: false,
+            planes: 1,
+            pixels: [[0; SCHIP_SCREEN_WIDTH]; SCHIP_SCREEN_HEIGTH],
+        }
+    }
+
+    pub fn width(&self) -> usize
+    {
+        if self.hires { SCHIP_SCREEN_WIDTH } else { C8_SCREEN_WIDTH }
+    }
+
+    pub fn height(&self) -> usize
+    {
+        if self.hires { SCHIP_SCREEN_HEIGTH } else { C8_SCREEN_HEIGTH }
+    }
+
+    pub fn is_hires(&self) -> bool
+    {
+        self.hires
+    }
+
+    pub fn set_hires(&mut self, hires: bool)
+    {
+        self.hires = hires;
+    }
+
+    pub fn planes(&self) -> u8
+    {
+        self.planes
+    }
+
+    pub fn set_planes(&mut self, planes: u8)
+    {
+        self.planes = planes & 0x3;
+    }
+
+    // Clears the selected planes
+    pub fn clear(&mut self)
+    {
+        let planes = self.planes;
+        for row in self.pixels.iter_mut()
+        {
+            for pixel in row.iter_mut()
+            {
+                *pixel &= !planes;
+            }
+        }
+    }
+
+    // Colour of a pixel, made of one bit per plane
+    pub fn get(&self, x: usize, y: usize) -> u8
+    {
+        self.pixels[y][x]
+    }
+
+    // Flips a pixel in one plane, returns true if it was turned off
+    pub fn toggle(&mut self, x: usize, y: usize, plane: u8) -> bool
+    {
+        let was_set = self.pixels[y][x] & plane != 0;
+        self.pixels[y][x] ^= plane;
+        was_set
+    }
+
+    // Moves the selected planes of the pixel at (from_x, from_y) to (x, y), the
+    // source being None when it is outside of the screen
+    fn move_pixel(&mut self, x: usize, y: usize, from: Option<(usize, usize)>)
+    {
+        let source = match from
+        {
+            Some((from_x, from_y)) => self.pixels[from_y][from_x] & self.planes,
+            None => 0,
+        };
+        self.pixels[y][x] = (self.pixels[y][x] & !self.planes) | source;
+    }
+
+    pub fn scroll_down(&mut self, n: usize)
+    {
+        let (width, height) = (self.width(), self.height());
+        for y in (0..height).rev()
+        {
+            for x in 0..width
+            {
+                self.move_pixel(x, y, if y >= n { Some((x, y - n)) } else { None });
+            }
+        }
+    }
+
+    pub fn scroll_up(&mut self, n: usize)
+    {
+        let (width, height) = (self.width(), self.height());
+        for y in 0..height
+        {
+            for x in 0..width
+            {
+                self.move_pixel(x, y, if y + n < height { Some((x, y + n)) } else { None });
+            }
+        }
+    }
+
+    pub fn scroll_right(&mut self, n: usize)
+    {
+        let (width, height) = (self.width(), self.height());
+        for y in 0..height
+        {
+            for x in (0..width).rev()
+            {
+                self.move_pixel(x, y, if x >= n { Some((x - n, y)) } else { None });
+            }
+        }
+    }
+
+    pub fn scroll_left(&mut self, n: usize)
+    {
+        let (width, height) = (self.width(), self.height());
+        for y in 0..height
+        {
+            for x in 0..width
+            {
+                self.move_pixel(x, y, if x + n < width { Some((x + n, y)) } else { None });
+            }
+        }
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter)
+    {
+        out.bool(self.hires);
+        out.u8(self.planes);
+        for row in self.pixels.iter()
+        {
+            out.bytes(row);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<()>
+    {
+        self.hires = state.bool()?;
+        self.planes = state.u8()? & 0x3;
+        for row in self.pixels.iter_mut()
+        {
+            row.copy_from_slice(state.bytes(SCHIP_SCREEN_WIDTH)?);
+        }
+        Ok(())
+    }
+}
+
+impl Default for Screen
+{
+    fn default() -> Screen
+    {
+        Screen::new()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::Screen;
+
+    // Coordinates and colour of the pixels set
+    fn lit(screen: &Screen) -> Vec<(usize, usize, u8)>
+    {
+        let mut pixels = Vec::new();
+        for y in 0..super::SCHIP_SCREEN_HEIGTH
+        {
+            for x in 0..super::SCHIP_SCREEN_WIDTH
+            {
+                if screen.get(x, y) != 0
+                {
+                    pixels.push((x, y, screen.get(x, y)));
+                }
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn scrolls_in_low_resolution()
+    {
+        let mut screen = Screen::new();
+        screen.toggle(0, 0, 1);
+        screen.toggle(63, 31, 1);
+        // Pixels leave the 64x32 screen instead of moving to the hidden part
+        screen.scroll_down(3);
+        assert_eq!(lit(&screen), vec![(0, 3, 1)]);
+        screen.scroll_right(4);
+        assert_eq!(lit(&screen), vec![(4, 3, 1)]);
+        screen.scroll_up(2);
+        assert_eq!(lit(&screen), vec![(4, 1, 1)]);
+        screen.scroll_left(4);
+        assert_eq!(lit(&screen), vec![(0, 1, 1)]);
+        screen.scroll_left(4);
+        assert!(lit(&screen).is_empty());
+    }
+
+    #[test]
+    fn scrolls_in_high_resolution()
+    {
+        let mut screen = Screen::new();
+        screen.set_hires(true);
+        screen.toggle(127, 0, 1);
+        screen.toggle(0, 60, 1);
+        screen.scroll_right(4);
+        assert_eq!(lit(&screen), vec![(4, 60, 1)]);
+        screen.scroll_down(3);
+        assert_eq!(lit(&screen), vec![(4, 63, 1)]);
+        screen.scroll_left(4);
+        assert_eq!(lit(&screen), vec![(0, 63, 1)]);
+        screen.scroll_down(1);
+        assert!(lit(&screen).is_empty());
+    }
+
+    #[test]
+    fn scrolls_the_selected_planes()
+    {
+        let mut screen = Screen::new();
+        screen.toggle(0, 0, 1);
+        screen.toggle(0, 0, 2);
+        screen.set_planes(2);
+        screen.scroll_down(1);
+        assert_eq!(lit(&screen), vec![(0, 0, 1), (0, 1, 2)]);
+    }
+}
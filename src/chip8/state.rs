@@ -0,0 +1,343 @@
+use std::fs;
+use std::path::PathBuf;
+use super::{Chip8, Variant, Result, ResultExt};
+
+// Save state layout, all the numbers are little endian:
+//  magic "C8ST" | version: u16 | payload length: u32 | crc32 of the payload: u32 | payload
+// The version must be bumped every time the payload changes
+const STATE_MAGIC: &[u8; 4] = b"C8ST";
+pub const STATE_VERSION: u16 = 3;
+const HEADER_SIZE: usize = 4 + 2 + 4 + 4;
+
+pub fn crc32(data: &[u8]) -> u32
+{
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data
+    {
+        crc ^= *byte as u32;
+        for _ in 0..8
+        {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    !crc
+}
+
+pub struct StateWriter
+{
+    data: Vec<u8>,
+}
+
+impl StateWriter
+{
+    pub fn new() -> StateWriter
+    {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8)
+    {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool)
+    {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16)
+    {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32)
+    {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64)
+    {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, value: &[u8])
+    {
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn into_inner(self) -> Vec<u8>
+    {
+        self.data
+    }
+}
+
+pub struct StateReader<'a>
+{
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a>
+{
+    pub fn new(data: &'a [u8]) -> StateReader<'a>
+    {
+        StateReader { data, position: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]>
+    {
+        if self.position + len > self.data.len()
+        {
+            bail!("Save state is truncated");
+        }
+        let bytes = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8>
+    {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool>
+    {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16>
+    {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32>
+    {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64>
+    {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.position == self.data.len()
+    }
+}
+
+pub fn variant_id(variant: Variant) -> u8
+{
+    match variant
+    {
+        Variant::Chip8 => 0,
+        Variant::SuperChip => 1,
+        Variant::XoChip => 2,
+    }
+}
+
+pub fn variant_from_id(id: u8) -> Option<Variant>
+{
+    match id
+    {
+        0 => Some(Variant::Chip8),
+        1 => Some(Variant::SuperChip),
+        2 => Some(Variant::XoChip),
+        _ => None,
+    }
+}
+
+impl Chip8
+{
+    // Serializes the whole machine into a versioned and checksummed save state
+    pub fn save_state(&self) -> Vec<u8>
+    {
+        let mut payload = StateWriter::new();
+        payload.u8(variant_id(self.variant));
+        payload.u32(self.mem.len() as u32);
+        payload.bytes(&self.mem);
+        payload.bytes(&self.v_registers);
+        payload.u32(self.program_counter as u32);
+        payload.u8(self.stack_pointer as u8);
+        for address in self.stack.iter()
+        {
+            payload.u32(*address as u32);
+        }
+        payload.u32(self.index as u32);
+        self.screen_memory.save_state(&mut payload);
+        payload.u8(self.dt);
+        payload.u8(self.st);
+        payload.u8(self.waiting_for_key.map_or(0xFF, |register| register));
+        payload.bool(self.waiting_for_vblank);
+        payload.bool(self.exited);
+        payload.bytes(&self.rpl_flags);
+        payload.bytes(&self.audio_pattern);
+        payload.u8(self.pitch);
+        payload.u32(self.rng.state());
+        payload.u64(self.frame);
+        let payload = payload.into_inner();
+
+        let mut state = StateWriter::new();
+        state.bytes(STATE_MAGIC);
+        state.u16(STATE_VERSION);
+        state.u32(payload.len() as u32);
+        state.u32(crc32(&payload));
+        state.bytes(&payload);
+        state.into_inner()
+    }
+
+    // Restores a state made by save_state, the machine is left untouched if it fails
+    pub fn load_state(&mut self, state: &[u8]) -> Result<()>
+    {
+        if state.len() < HEADER_SIZE || &state[0..4] != STATE_MAGIC
+        {
+            bail!("Not a save state");
+        }
+        let mut header = StateReader::new(&state[4..HEADER_SIZE]);
+        let version = header.u16()?;
+        if version != STATE_VERSION
+        {
+            bail!(format!("Save state version {} is not supported, this interpreter only loads version {}",
+                            version, STATE_VERSION));
+        }
+        let len = header.u32()? as usize;
+        let checksum = header.u32()?;
+        let payload = &state[HEADER_SIZE..];
+        if payload.len() != len || crc32(payload) != checksum
+        {
+            bail!("Save state is corrupted, checksum doesn't match");
+        }
+
+        let mut loaded = self.clone();
+        loaded.read_payload(&mut StateReader::new(payload)).chain_err(|| "Save state is corrupted")?;
+        *self = loaded;
+        Ok(())
+    }
+
+    fn read_payload(&mut self, payload: &mut StateReader) -> Result<()>
+    {
+        if payload.u8()? != variant_id(self.variant)
+        {
+            bail!(format!("Save state was made with a different mode than {:?}", self.variant));
+        }
+        let mem_len = payload.u32()? as usize;
+        if mem_len != self.mem.len()
+        {
+            bail!("Memory size doesn't match");
+        }
+        self.mem.copy_from_slice(payload.bytes(mem_len)?);
+        self.v_registers.copy_from_slice(payload.bytes(0x10)?);
+        self.program_counter = payload.u32()? as usize;
+        self.stack_pointer = payload.u8()? as usize;
+        for address in self.stack.iter_mut()
+        {
+            *address = payload.u32()? as usize;
+        }
+        if self.program_counter + 1 >= self.mem.len() || self.stack_pointer >= self.stack.len()
+        {
+            bail!("Registers out of range");
+        }
+        self.index = payload.u32()? as usize;
+        self.screen_memory.load_state(payload)?;
+        self.dt = payload.u8()?;
+        self.st = payload.u8()?;
+        self.waiting_for_key = match payload.u8()?
+        {
+            0xFF => None,
+            register => Some(register & 0xF),
+        };
+        self.waiting_for_vblank = payload.bool()?;
+        self.exited = payload.bool()?;
+        self.rpl_flags.copy_from_slice(payload.bytes(0x10)?);
+        self.audio_pattern.copy_from_slice(payload.bytes(0x10)?);
+        self.pitch = payload.u8()?;
+        self.rng.set_state(payload.u32()?);
+        self.frame = payload.u64()?;
+        if !payload.is_empty()
+        {
+            bail!("Unexpected data at the end");
+        }
+        self.draw = true;
+        Ok(())
+    }
+}
+
+// Numbered save state files stored next to the rom, ROM.state1, ROM.state2...
+pub struct SaveSlots
+{
+    rom_path: PathBuf,
+}
+
+impl SaveSlots
+{
+    pub fn new(rom_path: &str) -> SaveSlots
+    {
+        SaveSlots { rom_path: PathBuf::from(rom_path) }
+    }
+
+    pub fn path(&self, slot: u8) -> PathBuf
+    {
+        let mut path = self.rom_path.clone().into_os_string();
+        path.push(format!(".state{}", slot));
+        PathBuf::from(path)
+    }
+
+    pub fn save(&self, chip: &Chip8, slot: u8) -> Result<()>
+    {
+        let path = self.path(slot);
+        fs::write(&path, chip.save_state())
+            .chain_err(|| format!("Couldn't write save state {}", path.display()))
+    }
+
+    pub fn load(&self, chip: &mut Chip8, slot: u8) -> Result<()>
+    {
+        let path = self.path(slot);
+        let state = fs::read(&path)
+                        .chain_err(|| format!("Couldn't read save state {}", path.display()))?;
+        chip.load_state(&state)
+            .chain_err(|| format!("Couldn't load save state {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::super::{Chip8, Quirks, Variant};
+    use super::super::tests::run_frames;
+
+    #[test]
+    fn save_and_load_round_trip()
+    {
+        let rom = include_bytes!("../../roms/BRIX");
+        let mut chip = Chip8::new(Some(rom), Variant::Chip8, Quirks::default()).unwrap();
+        run_frames(&mut chip, 120);
+        let state = chip.save_state();
+        let mut copy = Chip8::new(None, Variant::Chip8, Quirks::default()).unwrap();
+        copy.load_state(&state).unwrap();
+        assert_eq!(copy.save_state(), state);
+        // Both machines go on the same way
+        run_frames(&mut chip, 120);
+        run_frames(&mut copy, 120);
+        assert_eq!(copy.save_state(), chip.save_state());
+    }
+
+    #[test]
+    fn corrupted_state_is_rejected()
+    {
+        let rom = include_bytes!("../../roms/BRIX");
+        let mut chip = Chip8::new(Some(rom), Variant::Chip8, Quirks::default()).unwrap();
+        run_frames(&mut chip, 60);
+        let mut state = chip.save_state();
+        let last = state.len() - 1;
+        state[last] ^= 1;
+        let mut copy = Chip8::new(None, Variant::Chip8, Quirks::default()).unwrap();
+        let before = copy.save_state();
+        assert!(copy.load_state(&state).is_err());
+        assert_eq!(copy.save_state(), before);
+    }
+}
@@ -0,0 +1,443 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use super::{Chip8, Result, ResultExt};
+use super::disasm;
+use super::fault::Fault;
+
+// First line of every trace, lines starting with # are comments
+pub const TRACE_HEADER: &str = "# cycle pc opcode mnemonic | changed registers | I DT ST";
+
+// Machine before an instruction, to log what it changed
+#[derive(Clone, Copy, Debug)]
+pub struct TraceSnapshot
+{
+    pc: usize,
+    opcode: u16,
+    next: Option<u16>,
+    v_registers: [u8; 0x10],
+}
+
+impl TraceSnapshot
+{
+    // None when the machine won't execute an instruction
+    pub fn of(chip: &Chip8) -> Option<TraceSnapshot>
+    {
+        if chip.is_waiting()
+        {
+            return None;
+        }
+        let pc = chip.program_counter;
+        let next = chip.mem.get(pc + 2..pc + 4).map(|word| (word[0] as u16) << 8 | word[1] as u16);
+        Some(TraceSnapshot { pc, opcode: chip.fetch_instruction(), next, v_registers: chip.v_registers })
+    }
+}
+
+// Inclusive range, either end can be left out: "200-2FF", "1000-", "-500"
+fn parse_range(text: &str, parse: fn(&str) -> Option<usize>) -> Option<(usize, usize)>
+{
+    let dash = text.find('-')?;
+    let (start, end) = (text[..dash].trim(), text[dash + 1..].trim());
+    let start = if start.is_empty() { 0 } else { parse(start)? };
+    let end = if end.is_empty() { usize::MAX } else { parse(end)? };
+    if start > end
+    {
+        return None;
+    }
+    Some((start, end))
+}
+
+// Logs every instruction executed to a file, one line each:
+//  1234 0204 2208 CALL 0x208 | - | I=0300 DT=3C ST=00
+//  1235 0208 6A05 LD VA, 0x05 | VA=05 | I=0300 DT=3C ST=00
+// The cycle counts the instructions from the start of the run, the changed
+// registers are the V registers the instruction wrote a different value to. An
+// instruction that faults is marked by a comment: # 1236 fault: Stack underflow ...
+pub struct Tracer
+{
+    writer: BufWriter<File>,
+    addresses: (usize, usize),
+    cycles: (usize, usize),
+    cycle: usize,
+    // First error writing the file, reported at the end of the run
+    error: Option<io::Error>,
+}
+
+impl Tracer
+{
+    pub fn create(path: &str) -> Result<Tracer>
+    {
+        let file = File::create(path).chain_err(|| format!("Couldn't create trace {}", path))?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "{}", TRACE_HEADER).chain_err(|| format!("Couldn't write trace {}", path))?;
+        Ok(Tracer { writer, addresses: (0, usize::MAX), cycles: (0, usize::MAX), cycle: 0, error: None })
+    }
+
+    // Only logs the instructions at addresses in the range, in hexadecimal: "200-2FF"
+    pub fn set_addresses(&mut self, range: &str) -> Result<()>
+    {
+        self.addresses = match parse_range(range, super::debugger::parse_number)
+        {
+            Some(addresses) => addresses,
+            None => bail!(format!("Invalid address range {}, expected START-END in hexadecimal", range)),
+        };
+        Ok(())
+    }
+
+    // Only logs the instructions executed in the range of cycles: "1000-2000"
+    pub fn set_cycles(&mut self, range: &str) -> Result<()>
+    {
+        self.cycles = match parse_range(range, |number| number.parse().ok())
+        {
+            Some(cycles) => cycles,
+            None => bail!(format!("Invalid cycle range {}, expected START-END", range)),
+        };
+        Ok(())
+    }
+
+    // Logs the instruction executed since the snapshot was taken
+    pub fn log(&mut self, before: &TraceSnapshot, chip: &Chip8)
+    {
+        let cycle = self.cycle;
+        self.cycle += 1;
+        let in_range = |value, (start, end)| start <= value && value <= end;
+        if self.error.is_some() || !in_range(before.pc, self.addresses) || !in_range(cycle, self.cycles)
+        {
+            return;
+        }
+        let mnemonic = match disasm::decode(before.opcode, before.next, chip.variant)
+        {
+            Some(instruction) => instruction.mnemonic,
+            None => format!("DW {:#06X}", before.opcode),
+        };
+        let changed: Vec<String> = (0..0x10)
+            .filter(|&i| chip.v_registers[i] != before.v_registers[i])
+            .map(|i| format!("V{:X}={:02X}", i, chip.v_registers[i]))
+            .collect();
+        let changed = if changed.is_empty() { "-".to_string() } else { changed.join(" ") };
+        if let Err(e) = writeln!(self.writer, "{} {:04X} {:04X} {} | {} | I={:04X} DT={:02X} ST={:02X}",
+                                    cycle, before.pc, before.opcode, mnemonic, changed, chip.index, chip.dt, chip.st)
+        {
+            self.error = Some(e);
+        }
+    }
+
+    // Marks an instruction that faulted, as a comment since it had no effect
+    pub fn log_fault(&mut self, fault: &Fault)
+    {
+        if self.error.is_some()
+        {
+            return;
+        }
+        if let Err(e) = writeln!(self.writer, "# {} fault: {}", self.cycle, fault)
+        {
+            self.error = Some(e);
+        }
+    }
+
+    // Writes what's left in the buffer, failing if any line couldn't be written
+    pub fn finish(&mut self) -> Result<()>
+    {
+        if let Some(e) = self.error.take()
+        {
+            return Err(e).chain_err(|| "Couldn't write trace");
+        }
+        self.writer.flush().chain_err(|| "Couldn't write trace")
+    }
+}
+
+// One instruction of a trace file, of this interpreter or of another one writing the
+// same format
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry
+{
+    // Line of the file, from 1
+    pub line: usize,
+    pub text: String,
+    pub pc: usize,
+    pub opcode: u16,
+    // Sorted, so the order they are written in doesn't matter
+    pub changed: Vec<String>,
+    pub index: usize,
+    pub dt: u8,
+    pub st: u8,
+}
+
+fn parse_entry(line: usize, text: &str) -> Option<TraceEntry>
+{
+    let parts: Vec<&str> = text.split(" | ").map(str::trim).collect();
+    if parts.len() != 3
+    {
+        return None;
+    }
+    let mut fields = parts[0].split_whitespace().skip(1);
+    let pc = usize::from_str_radix(fields.next()?, 16).ok()?;
+    let opcode = u16::from_str_radix(fields.next()?, 16).ok()?;
+    let mut changed: Vec<String> = parts[1].split_whitespace().filter(|&register| register != "-")
+                                            .map(str::to_uppercase).collect();
+    changed.sort();
+    let (mut index, mut dt, mut st) = (None, None, None);
+    for field in parts[2].split_whitespace()
+    {
+        let equal = field.find('=')?;
+        let value = usize::from_str_radix(&field[equal + 1..], 16).ok()?;
+        match &field[..equal]
+        {
+            "I" => index = Some(value),
+            "DT" => dt = Some(value as u8),
+            "ST" => st = Some(value as u8),
+            _ => return None,
+        }
+    }
+    Some(TraceEntry { line, text: text.to_string(), pc, opcode, changed, index: index?, dt: dt?, st: st? })
+}
+
+// Reads the instructions of a trace, skipping comments and empty lines
+pub fn parse_trace(text: &str) -> Result<Vec<TraceEntry>>
+{
+    let mut entries = Vec::new();
+    for (number, line) in text.lines().enumerate()
+    {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#')
+        {
+            continue;
+        }
+        match parse_entry(number + 1, line)
+        {
+            Some(entry) => entries.push(entry),
+            None => bail!(format!("Line {}: expected CYCLE PC OPCODE MNEMONIC | REGISTERS | I=.. DT=.. ST=..",
+                                    number + 1)),
+        }
+    }
+    Ok(entries)
+}
+
+// First instruction two traces disagree on, as positions in each of them. A position
+// past the end means that trace stopped first
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence
+{
+    pub a: usize,
+    pub b: usize,
+    // Matching instructions before the divergence
+    pub matching: usize,
+    pub differences: Vec<String>,
+}
+
+fn differences(a: &TraceEntry, b: &TraceEntry, timers: bool) -> Vec<String>
+{
+    let mut differences = Vec::new();
+    if a.pc != b.pc
+    {
+        differences.push(format!("pc {:04X} != {:04X}", a.pc, b.pc));
+    }
+    if a.opcode != b.opcode
+    {
+        differences.push(format!("opcode {:04X} != {:04X}", a.opcode, b.opcode));
+    }
+    if a.changed != b.changed
+    {
+        differences.push(format!("changed registers {} != {}", a.changed.join(" "), b.changed.join(" ")));
+    }
+    if a.index != b.index
+    {
+        differences.push(format!("I {:04X} != {:04X}", a.index, b.index));
+    }
+    if timers && a.dt != b.dt
+    {
+        differences.push(format!("DT {:02X} != {:02X}", a.dt, b.dt));
+    }
+    if timers && a.st != b.st
+    {
+        differences.push(format!("ST {:02X} != {:02X}", a.st, b.st));
+    }
+    differences
+}
+
+// Starting points tried when aligning, for each trace
+const MAX_ALIGNMENTS: usize = 1000;
+
+// Instructions matching from the start of both
+fn matching_run(a: &[TraceEntry], b: &[TraceEntry], timers: bool) -> usize
+{
+    a.iter().zip(b.iter()).take_while(|(x, y)| differences(x, y, timers).is_empty()).count()
+}
+
+// Finds where the traces start matching, in case one of them started logging later.
+// This is a heuristic: the first instruction of each trace is looked for in the other
+// one, and the starting points giving the longest run of matching instructions win,
+// the earliest ones on a tie. In a loop the first instruction comes back every
+// iteration, and only the right iteration matches for long
+fn align(a: &[TraceEntry], b: &[TraceEntry], timers: bool) -> (usize, usize)
+{
+    let same_instruction = |x: &TraceEntry, y: &TraceEntry| x.pc == y.pc && x.opcode == y.opcode;
+    let positions = |entries: &[TraceEntry], first: Option<&TraceEntry>| -> Vec<usize>
+    {
+        match first
+        {
+            Some(first) => (0..entries.len()).filter(|&i| same_instruction(&entries[i], first))
+                                                .take(MAX_ALIGNMENTS).collect(),
+            None => Vec::new(),
+        }
+    };
+    let mut candidates = vec![(0, 0)];
+    candidates.extend(positions(b, a.first()).into_iter().map(|j| (0, j)));
+    candidates.extend(positions(a, b.first()).into_iter().map(|i| (i, 0)));
+    let mut best = (0, (0, 0));
+    for (i, j) in candidates
+    {
+        let run = matching_run(&a[i..], &b[j..], timers);
+        if run > best.0 || (run == best.0 && i + j < (best.1).0 + (best.1).1)
+        {
+            best = (run, (i, j));
+        }
+    }
+    best.1
+}
+
+// Aligns the traces, then compares them instruction by instruction. The cycles and
+// the mnemonics aren't compared, they differ between emulators.
+// Returns None if they agree until both end
+pub fn first_divergence(a: &[TraceEntry], b: &[TraceEntry], timers: bool) -> Option<Divergence>
+{
+    let (start_a, start_b) = align(a, b, timers);
+    let mut matching = 0;
+    loop
+    {
+        let (i, j) = (start_a + matching, start_b + matching);
+        let differences = match (a.get(i), b.get(j))
+        {
+            (None, None) => return None,
+            (Some(_), None) => vec!["the second trace ends".to_string()],
+            (None, Some(_)) => vec!["the first trace ends".to_string()],
+            (Some(x), Some(y)) => differences(x, y, timers),
+        };
+        if !differences.is_empty()
+        {
+            return Some(Divergence { a: i, b: j, matching, differences });
+        }
+        matching += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::fs;
+    use super::{first_divergence, parse_trace, TraceEntry, Tracer, TRACE_HEADER};
+    use super::super::{Chip8, Quirks, Variant};
+    use super::super::frontend::{Composite, NullBuzzer, NullKeypad};
+    use super::super::headless::HeadlessDisplay;
+    use super::super::runner::Runner;
+    use super::super::scheduler::VirtualClock;
+
+    // A loop counting in V0, every iteration is different
+    fn counting_loop(iterations: usize) -> String
+    {
+        let mut text = String::new();
+        for i in 0..iterations
+        {
+            text.push_str(&format!("{} 0200 7001 ADD V0, 0x01 | V0={:02X} | I=0000 DT=00 ST=00\n", i * 2, i + 1));
+            text.push_str(&format!("{} 0202 1200 JP 0x200 | - | I=0000 DT=00 ST=00\n", i * 2 + 1));
+        }
+        text
+    }
+
+    fn trace(text: &str) -> Vec<TraceEntry>
+    {
+        parse_trace(text).unwrap()
+    }
+
+    #[test]
+    fn aligns_a_trace_started_later()
+    {
+        let full = trace(&counting_loop(50));
+        let later = full[20..].to_vec();
+        assert_eq!(first_divergence(&full, &later, true), None);
+        assert_eq!(first_divergence(&later, &full, true), None);
+    }
+
+    #[test]
+    fn finds_the_first_difference()
+    {
+        let a = trace(&counting_loop(50));
+        let mut b = a[10..].to_vec();
+        b[30] = trace("40 0200 7001 ADD V0, 0x01 | V0=FF | I=0000 DT=00 ST=00")[0].clone();
+        let divergence = first_divergence(&a, &b, true).unwrap();
+        assert_eq!((divergence.a, divergence.b, divergence.matching), (40, 30, 30));
+        assert_eq!(divergence.differences, vec!["changed registers V0=15 != V0=FF".to_string()]);
+    }
+
+    #[test]
+    fn rejects_malformed_lines()
+    {
+        assert!(parse_trace("# comment\n0 0200 7001 ADD V0, 0x01 | V0=01 | I=0000 DT=00").is_err());
+        assert!(parse_trace("0 0200 7001 ADD V0, 0x01 V0=01").is_err());
+    }
+
+    // Trace of a frame of a rom ending in a stack underflow, with the filters set by
+    // `filter`
+    fn trace_of(name: &str, filter: fn(&mut Tracer)) -> String
+    {
+        // LD V0, 5 ; LD I, 0x300 ; ADD V0, 1 ; RET
+        let rom = [0x60, 0x05, 0xA3, 0x00, 0x70, 0x01, 0x00, 0xEE];
+        let path = std::env::temp_dir().join(format!("chip8_trace_{}_{}", name, std::process::id()));
+        let mut tracer = Tracer::create(&path.to_string_lossy()).unwrap();
+        filter(&mut tracer);
+        let mut runner = Runner::new();
+        runner.tracer = Some(tracer);
+        let mut chip = Chip8::new(Some(&rom), Variant::Chip8, Quirks::default()).unwrap();
+        let mut frontend = Composite { display: HeadlessDisplay::new(1), keypad: NullKeypad, buzzer: NullBuzzer };
+        assert!(runner.run_with_clock(&mut chip, &mut frontend, &mut VirtualClock::new()).is_err());
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        text
+    }
+
+    const FAULT: &str = "# 3 fault: Stack underflow executing instruction 0x00EE at 0x0206";
+
+    #[test]
+    fn writes_every_instruction_and_the_fault()
+    {
+        let text = trace_of("all", |_| {});
+        let expected = [
+            TRACE_HEADER,
+            "0 0200 6005 LD V0, 0x05 | V0=05 | I=0000 DT=00 ST=00",
+            "1 0202 A300 LD I, 0x300 | - | I=0300 DT=00 ST=00",
+            "2 0204 7001 ADD V0, 0x01 | V0=06 | I=0300 DT=00 ST=00",
+            FAULT,
+        ];
+        assert_eq!(text.lines().collect::<Vec<_>>(), expected);
+        // The trace reads back
+        assert_eq!(trace(&text).iter().map(|entry| entry.pc).collect::<Vec<_>>(), [0x200, 0x202, 0x204]);
+    }
+
+    #[test]
+    fn filters()
+    {
+        let text = trace_of("addresses", |tracer| tracer.set_addresses("202-0x204").unwrap());
+        let pcs: Vec<usize> = trace(&text).iter().map(|entry| entry.pc).collect();
+        assert_eq!(pcs, [0x202, 0x204]);
+        assert!(text.ends_with(&format!("{}\n", FAULT)));
+        let text = trace_of("cycles", |tracer| tracer.set_cycles("-1").unwrap());
+        let pcs: Vec<usize> = trace(&text).iter().map(|entry| entry.pc).collect();
+        assert_eq!(pcs, [0x200, 0x202]);
+        let text = trace_of("later", |tracer| tracer.set_cycles("2-").unwrap());
+        let pcs: Vec<usize> = trace(&text).iter().map(|entry| entry.pc).collect();
+        assert_eq!(pcs, [0x204]);
+    }
+
+    #[test]
+    fn invalid_ranges()
+    {
+        let path = std::env::temp_dir().join(format!("chip8_trace_ranges_{}", std::process::id()));
+        let mut tracer = Tracer::create(&path.to_string_lossy()).unwrap();
+        assert!(tracer.set_addresses("300-200").is_err());
+        assert!(tracer.set_addresses("200").is_err());
+        assert!(tracer.set_addresses("xyz-").is_err());
+        assert!(tracer.set_cycles("10-5").is_err());
+        assert!(tracer.set_cycles("1A-").is_err());
+        drop(tracer);
+        fs::remove_file(&path).unwrap();
+    }
+}
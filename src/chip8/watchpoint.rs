@@ -0,0 +1,220 @@
+use std::fmt;
+use super::Chip8;
+use super::debugger::parse_number;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchTarget
+{
+    Memory(usize),
+    Register(u8),
+    Index,
+}
+
+// Condition checked by the debugger after every instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint
+{
+    pub target: WatchTarget,
+    // Only meaningful for memory, registers are watched for changes
+    pub read: bool,
+    pub write: bool,
+    // Only trigger when the value read, written or taken by the register is this one
+    pub value: Option<usize>,
+}
+
+// Registers before an instruction, to find out which ones it changed
+#[derive(Clone, Copy, Debug)]
+pub struct RegisterSnapshot
+{
+    v_registers: [u8; 0x10],
+    index: usize,
+}
+
+impl RegisterSnapshot
+{
+    pub fn of(chip: &Chip8) -> RegisterSnapshot
+    {
+        RegisterSnapshot { v_registers: chip.v_registers, index: chip.index }
+    }
+}
+
+impl Watchpoint
+{
+    // Parses the arguments of the watch command: TARGET [r|w|rw] [== VALUE]
+    // where TARGET is a memory address, a V register or I
+    pub fn parse(arguments: &[&str]) -> Option<Watchpoint>
+    {
+        let (target, mut rest) = arguments.split_first()?;
+        let target = match target.to_uppercase().as_str()
+        {
+            "I" => WatchTarget::Index,
+            register if register.len() == 2 && register.starts_with('V') =>
+                WatchTarget::Register(u8::from_str_radix(&register[1..], 16).ok()?),
+            address => WatchTarget::Memory(parse_number(address)?),
+        };
+        let (mut read, mut write) = (true, true);
+        if let WatchTarget::Memory(_) = target
+        {
+            if let Some((access, others)) = rest.split_first()
+            {
+                match *access
+                {
+                    "r" => { write = false; rest = others; }
+                    "w" => { read = false; rest = others; }
+                    "rw" => { rest = others; }
+                    _ => {}
+                }
+            }
+        }
+        let value = match rest
+        {
+            [] => None,
+            ["==", value] => Some(parse_number(value)?),
+            _ => return None,
+        };
+        Some(Watchpoint { target, read, write, value })
+    }
+
+    // Checks the last instruction executed, returning what it did to the target
+    pub fn check(&self, chip: &Chip8, before: &RegisterSnapshot) -> Option<String>
+    {
+        let matches_value = |value: usize| self.value.is_none_or(|expected| expected == value);
+        match self.target
+        {
+            WatchTarget::Memory(address) =>
+            {
+                chip.mem_accesses().iter()
+                    .find(|access| access.address == address &&
+                                    (if access.write { self.write } else { self.read }) &&
+                                    matches_value(access.value as usize))
+                    .map(|access| if access.write
+                                    { format!("wrote {:02X} to {:04X}", access.value, address) }
+                                  else
+                                    { format!("read {:02X} from {:04X}", access.value, address) })
+            }
+            WatchTarget::Register(register) =>
+            {
+                let (old, new) = (before.v_registers[register as usize], chip.v_registers[register as usize]);
+                if old != new && matches_value(new as usize)
+                {
+                    Some(format!("V{:X} changed from {:02X} to {:02X}", register, old, new))
+                }
+                else
+                {
+                    None
+                }
+            }
+            WatchTarget::Index =>
+            {
+                let (old, new) = (before.index, chip.index);
+                if old != new && matches_value(new)
+                {
+                    Some(format!("I changed from {:04X} to {:04X}", old, new))
+                }
+                else
+                {
+                    None
+                }
+            }
+        }
+    }
+
+    pub fn watches_memory(&self) -> bool
+    {
+        matches!(self.target, WatchTarget::Memory(_))
+    }
+}
+
+impl fmt::Display for Watchpoint
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self.target
+        {
+            WatchTarget::Memory(address) =>
+            {
+                let access = match (self.read, self.write)
+                {
+                    (true, false) => "r",
+                    (false, true) => "w",
+                    _ => "rw",
+                };
+                write!(f, "{:04X} {}", address, access)?;
+            }
+            WatchTarget::Register(register) => write!(f, "V{:X}", register)?,
+            WatchTarget::Index => write!(f, "I")?,
+        }
+        if let Some(value) = self.value
+        {
+            write!(f, " == {:X}", value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{RegisterSnapshot, Watchpoint, WatchTarget};
+    use super::super::{Chip8, Quirks, Variant};
+
+    fn parse(command: &str) -> Option<Watchpoint>
+    {
+        Watchpoint::parse(&command.split_whitespace().collect::<Vec<_>>())
+    }
+
+    // What the watchpoint saw of every instruction of `rom`
+    fn hits(watchpoint: &Watchpoint, rom: &[u8], steps: usize) -> Vec<Option<String>>
+    {
+        let mut chip = Chip8::new(Some(rom), Variant::Chip8, Quirks::default()).unwrap();
+        chip.set_memory_tracking(true);
+        (0..steps).map(|_|
+        {
+            let before = RegisterSnapshot::of(&chip);
+            chip.step().unwrap();
+            watchpoint.check(&chip, &before)
+        }).collect()
+    }
+
+    #[test]
+    fn parses_the_watch_arguments()
+    {
+        let watchpoint = parse("300 w == 5").unwrap();
+        assert_eq!(watchpoint, Watchpoint { target: WatchTarget::Memory(0x300), read: false, write: true,
+                                            value: Some(5) });
+        assert_eq!(watchpoint.to_string(), "0300 w == 5");
+        assert_eq!(parse("vA").unwrap().target, WatchTarget::Register(0xA));
+        assert_eq!(parse("i == 2F0").unwrap().to_string(), "I == 2F0");
+        assert_eq!(parse("300").unwrap().to_string(), "0300 rw");
+        assert_eq!(parse("VG"), None);
+        assert_eq!(parse("V1 w"), None);
+        assert_eq!(parse("300 r 5"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn hit_on_write_in_a_range()
+    {
+        // LD I, 0x300 ; LD V2, 7 ; LD [I], V3 ; LD V3, [I]
+        let rom = [0xA3, 0x00, 0x62, 0x07, 0xF3, 0x55, 0xF3, 0x65];
+        let writes = parse("302 w").unwrap();
+        assert_eq!(hits(&writes, &rom, 4), vec![None, None, Some("wrote 07 to 0302".to_string()), None]);
+        let reads = parse("302 r == 7").unwrap();
+        assert_eq!(hits(&reads, &rom, 4), vec![None, None, None, Some("read 07 from 0302".to_string())]);
+        let other_value = parse("302 == 6").unwrap();
+        assert_eq!(hits(&other_value, &rom, 4), vec![None; 4]);
+    }
+
+    #[test]
+    fn hit_on_register_change()
+    {
+        // LD V2, 7 ; LD V2, 7 ; ADD V2, 1 ; LD I, 0x300
+        let rom = [0x62, 0x07, 0x62, 0x07, 0x72, 0x01, 0xA3, 0x00];
+        assert_eq!(hits(&parse("V2").unwrap(), &rom, 4),
+                    vec![Some("V2 changed from 00 to 07".to_string()), None,
+                         Some("V2 changed from 07 to 08".to_string()), None]);
+        assert_eq!(hits(&parse("V2 == 8").unwrap(), &rom, 4)[..3],
+                    [None, None, Some("V2 changed from 07 to 08".to_string())]);
+        assert_eq!(hits(&parse("I").unwrap(), &rom, 4)[3], Some("I changed from 0000 to 0300".to_string()));
+    }
+}
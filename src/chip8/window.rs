@@ -0,0 +1,167 @@
+use minifb::{Window, WindowOptions, Key, KeyRepeat};
+use super::{Result, ResultExt};
+use super::frontend::{Display, Keypad, Buzzer, Screen, Command};
+use super::keymap::Keymap;
+use super::screen::{SCHIP_SCREEN_WIDTH, SCHIP_SCREEN_HEIGTH, PALETTE};
+
+// The window always has the size of the high resolution screen, low resolution
+// pixels take 2x2 window pixels
+const SCALE: minifb::Scale = minifb::Scale::X8;
+
+// F1-F4 load the save state slot with their number, pressed with shift they save it
+const SLOT_KEYS: [(u8, Key); 4] = [(1, Key::F1), (2, Key::F2), (3, Key::F3), (4, Key::F4)];
+// Held down to go back in time
+const REWIND_KEY: Key = Key::Backspace;
+// Change the number of instructions per second
+const SPEED_UP_KEY: Key = Key::Equal;
+const SLOW_DOWN_KEY: Key = Key::Minus;
+const PAUSE_KEY: Key = Key::P;
+// Held down to run as fast as possible or at a quarter of the speed
+const FAST_FORWARD_KEY: Key = Key::Tab;
+const SLOW_MOTION_KEY: Key = Key::Backquote;
+// Every key above, they can't be bound to the keypad
+pub const HOTKEYS: [Key; 10] = [Key::F1, Key::F2, Key::F3, Key::F4, REWIND_KEY, SPEED_UP_KEY, SLOW_DOWN_KEY,
+                                PAUSE_KEY, FAST_FORWARD_KEY, SLOW_MOTION_KEY];
+
+const TITLE: &str = "Chip-8 Emulator by Satore";
+
+// minifb frontend, shows the screen in a window and reads the keypad from the keyboard
+pub struct WindowFrontend
+{
+    window: Window,
+    keymap: Keymap,
+    // minifb can't play sound, the buzzer is forwarded to this
+    audio: Option<Box<dyn Buzzer>>,
+}
+
+impl WindowFrontend
+{
+    pub fn new() -> Result<WindowFrontend>
+    {
+        Ok(WindowFrontend
+        {
+            window: Window::new(TITLE,
+                                SCHIP_SCREEN_WIDTH, SCHIP_SCREEN_HEIGTH,
+                                    WindowOptions
+                                    {
+                                        borderless: false,
+                                        title: true,
+                                        resize: false,
+                                        scale: SCALE
+                                    }).chain_err(|| "Couldn't create window")?,
+            keymap: Keymap::default(),
+            audio: None,
+        })
+    }
+
+    pub fn set_keymap(&mut self, keymap: Keymap)
+    {
+        self.keymap = keymap;
+    }
+
+    pub fn set_audio(&mut self, audio: Box<dyn Buzzer>)
+    {
+        self.audio = Some(audio);
+    }
+
+    fn get_screen_buffer(screen: &Screen) -> Vec<u32>
+    {
+        let mut buffer = vec![0u32; SCHIP_SCREEN_WIDTH * SCHIP_SCREEN_HEIGTH];
+        let pixel_size = if screen.is_hires() { 1 } else { 2 };
+        for (i, pixel) in buffer.iter_mut().enumerate()
+        {
+            let (x, y) = (i % SCHIP_SCREEN_WIDTH, i / SCHIP_SCREEN_WIDTH);
+            *pixel = PALETTE[screen.get(x / pixel_size, y / pixel_size) as usize];
+        }
+        buffer
+    }
+}
+
+impl Display for WindowFrontend
+{
+    fn draw(&mut self, screen: &Screen) -> Result<()>
+    {
+        self.window.update_with_buffer(&WindowFrontend::get_screen_buffer(screen))
+                                        .chain_err(|| "Error updating screen")
+    }
+
+    fn refresh(&mut self) -> Result<()>
+    {
+        self.window.update();
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool
+    {
+        self.window.is_open()
+    }
+
+    fn set_status(&mut self, status: &str)
+    {
+        self.window.set_title(&format!("{} - {}", TITLE, status));
+    }
+}
+
+impl Keypad for WindowFrontend
+{
+    fn is_key_down(&self, hexcode: u8) -> bool
+    {
+        self.keymap.host_keys(hexcode).iter().any(|key| self.window.is_key_down(*key))
+    }
+
+    fn commands(&mut self) -> Vec<Command>
+    {
+        let mut commands = Vec::new();
+        if self.window.is_key_down(REWIND_KEY)
+        {
+            commands.push(Command::Rewind);
+        }
+        if self.window.is_key_down(FAST_FORWARD_KEY)
+        {
+            commands.push(Command::FastForward);
+        }
+        if self.window.is_key_down(SLOW_MOTION_KEY)
+        {
+            commands.push(Command::SlowMotion);
+        }
+        let shift = self.window.is_key_down(Key::LeftShift) || self.window.is_key_down(Key::RightShift);
+        if let Some(keys) = self.window.get_keys_pressed(KeyRepeat::No)
+        {
+            for (key, command) in [(SPEED_UP_KEY, Command::SpeedUp), (SLOW_DOWN_KEY, Command::SlowDown),
+                                    (PAUSE_KEY, Command::TogglePause)].iter()
+            {
+                if keys.contains(key)
+                {
+                    commands.push(*command);
+                }
+            }
+            for (slot, k) in SLOT_KEYS.iter()
+            {
+                if keys.contains(k)
+                {
+                    commands.push(if shift { Command::SaveState(*slot) } else { Command::LoadState(*slot) });
+                }
+            }
+        }
+        commands
+    }
+}
+
+impl Buzzer for WindowFrontend
+{
+    fn set_buzzing(&mut self, buzzing: bool)
+    {
+        if let Some(ref mut audio) = self.audio
+        {
+            audio.set_buzzing(buzzing);
+        }
+    }
+
+    fn set_pattern(&mut self, pattern: &[u8; 0x10], rate: f32)
+    {
+        if let Some(ref mut audio) = self.audio
+        {
+            audio.set_pattern(pattern, rate);
+        }
+    }
+}
@@ -1,26 +1,68 @@
 #[macro_use]
 extern crate error_chain;
 
-use std::fs::File;
-use std::io::Read;
-use argparse::{ArgumentParser, Store};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::io::{self, Read};
+use argparse::{ArgumentParser, Store, StoreOption, StoreTrue};
 
 mod chip8;
-use chip8::{Chip8, Result, ResultExt};
+use chip8::{Chip8, Quirks, Rng, RngKind, Variant, Error, ErrorKind, Result, ResultExt, PROGRAM_START};
+use chip8::asm;
+use chip8::audio::{self, Audio, CommandSink, NullSink, Tone, WavSink, Waveform};
+use chip8::crash;
+use chip8::frontend::{Buzzer, Composite, NullBuzzer, NullKeypad};
+use chip8::debugger::Debugger;
+use chip8::disasm;
+use chip8::fault::FaultPolicies;
+use chip8::headless::{self, HeadlessDisplay};
+use chip8::image::{self, ImageFormat};
+use chip8::input::InputScript;
+use chip8::keymap::{self, KeymapConfig};
+use chip8::movie::{Movie, MovieHeader, MoviePlayer, MovieRecorder};
+use chip8::octo;
+use chip8::rewind::Rewind;
+use chip8::runner::Runner;
+use chip8::scheduler::{Scheduler, VirtualClock};
+use chip8::state::{crc32, SaveSlots};
+use chip8::trace::{self, TraceEntry, Tracer};
+use chip8::window::WindowFrontend;
 
-fn load_rom(rom_name: String) -> Result<[u8; 0xE00]>
+fn is_octo_source(file_name: &str) -> bool
 {
+    Path::new(file_name).extension().is_some_and(|extension| extension == "8o")
+}
+
+// Octo sources are compiled on the fly, anything else is loaded as is
+fn load_rom(rom_name: String) -> Result<Vec<u8>>
+{
+    if is_octo_source(&rom_name)
+    {
+        let source = fs::read_to_string(&rom_name)?;
+        return octo::assemble(&source).chain_err(|| format!("Error compiling {}", rom_name));
+    }
     let mut rom = File::open(&rom_name)?;
     let mut brom: Vec<u8> = Vec::new();
     rom.read_to_end(&mut brom)?;
-    let mut f_rom = [0u8; 0xE00];
-    if brom.len() > f_rom.len()
+    Ok(brom)
+}
+
+fn parse_variant(variant_name: &str) -> Result<Variant>
+{
+    match Variant::from_name(variant_name)
+    {
+        Some(variant) => Ok(variant),
+        None => bail!(format!("Unknown mode {}, expected chip8, schip or xochip", variant_name)),
+    }
+}
+
+// Parses the arguments of a subcommand, exiting like parse_args_or_exit on errors or --help
+fn parse_or_exit(ap: &ArgumentParser, args: Vec<String>)
+{
+    if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr())
     {
-        bail!(format!("Rom can't be bigger than {}B and {} is {}B",
-                                        f_rom.len(), rom_name , brom.len()));
+        std::process::exit(code);
     }
-    (&mut f_rom[..brom.len()]).copy_from_slice(&brom[..]);
-    Ok(f_rom)
 }
 
 
@@ -44,21 +86,487 @@ fn main()
     }
 }
 
+// Removes the subcommand from the arguments, keeping it in the program name for the usage
+fn subcommand_args(mut args: Vec<String>) -> Vec<String>
+{
+    let command = args.remove(1);
+    args[0] = format!("{} {}", args[0], command);
+    args
+}
+
+// `chip8 COMMAND ...`, a bare rom name runs it like `chip8 run ROM`
 fn run() -> Result<()>
 {
+    let args: Vec<String> = std::env::args().collect();
+    let command = args.get(1).cloned().unwrap_or_default();
+    match command.as_str()
+    {
+        "run" => run_rom(subcommand_args(args)),
+        "asm" => assemble(subcommand_args(args)),
+        "disasm" => disassemble(subcommand_args(args)),
+        "tracediff" => trace_diff(subcommand_args(args)),
+        _ => run_rom(args),
+    }
+}
+
+fn assemble(args: Vec<String>) -> Result<()>
+{
+    let mut source_name = String::new();
     let mut rom_name = String::new();
     {
-        // this block limits scope of borrows by ap.refer() method
         let mut ap = ArgumentParser::new();
-        ap.set_description("Chip-8 interpreter by Satore");
+        ap.set_description("Assembles a source file into a rom");
+        ap.refer(&mut source_name)
+            .add_argument("SOURCE", Store,
+                "File containing the assembly, in Octo syntax if its extension is 8o").required();
+        ap.refer(&mut rom_name)
+            .add_option(&["-o", "--output"], Store,
+                "File to write the rom to, defaults to SOURCE with the ch8 extension");
+        parse_or_exit(&ap, args);
+    }
+
+    if rom_name.is_empty()
+    {
+        rom_name = Path::new(&source_name).with_extension("ch8").to_string_lossy().into_owned();
+    }
+    let source = fs::read_to_string(&source_name).chain_err(|| "Error loading source")?;
+    let rom = if is_octo_source(&source_name)
+    {
+        octo::assemble(&source)
+    }
+    else
+    {
+        asm::assemble(&source)
+    };
+    let rom = rom.chain_err(|| format!("Error assembling {}", source_name))?;
+    fs::write(&rom_name, &rom).chain_err(|| "Error writing rom")?;
+    println!("Wrote {} bytes to {}", rom.len(), rom_name);
+    Ok(())
+}
+
+fn disassemble(args: Vec<String>) -> Result<()>
+{
+    let mut rom_name = String::new();
+    let mut variant_name = "chip8".to_string();
+    let mut recursive = false;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Prints the instructions of a rom");
         ap.refer(&mut rom_name)
             .add_argument("ROM", Store,
                 "File containing the rom").required();
-                ap.parse_args_or_exit();
+        ap.refer(&mut variant_name)
+            .add_option(&["-m", "--mode"], Store,
+                "Instruction set: chip8, schip or xochip");
+        ap.refer(&mut recursive)
+            .add_option(&["-r", "--recursive"], StoreTrue,
+                "Follow jumps, calls and skips from the entry point, showing the rest as data");
+        parse_or_exit(&ap, args);
     }
 
+    let variant = parse_variant(&variant_name)?;
     let rom = load_rom(rom_name).chain_err(|| "Error loading rom")?;
-    let mut chip = Chip8::new(Some(rom)).chain_err(|| "Error creating Chip8 struct")?;
-    chip.auto_run().chain_err(|| "Error executing rom")?;
+    let lines = if recursive
+    {
+        disasm::recursive(&rom, PROGRAM_START, variant)
+    }
+    else
+    {
+        disasm::linear(&rom, PROGRAM_START, variant)
+    };
+    for line in lines.iter()
+    {
+        println!("{}", disasm::format_line(line));
+    }
     Ok(())
 }
+
+fn load_trace(name: &str) -> Result<Vec<TraceEntry>>
+{
+    let text = fs::read_to_string(name).chain_err(|| format!("Error loading trace {}", name))?;
+    trace::parse_trace(&text).chain_err(|| format!("Error in trace {}", name))
+}
+
+fn trace_diff(args: Vec<String>) -> Result<()>
+{
+    let mut first_name = String::new();
+    let mut second_name = String::new();
+    let mut context = 5usize;
+    let mut ignore_timers = false;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Compares two traces written with --trace, showing where they diverge");
+        ap.refer(&mut first_name)
+            .add_argument("A", Store,
+                "First trace").required();
+        ap.refer(&mut second_name)
+            .add_argument("B", Store,
+                "Second trace").required();
+        ap.refer(&mut context)
+            .add_option(&["-C", "--context"], Store,
+                "Instructions shown before and after the divergence");
+        ap.refer(&mut ignore_timers)
+            .add_option(&["--ignore-timers"], StoreTrue,
+                "Don't compare the delay and sound timers, for emulators ticking them at other times");
+        parse_or_exit(&ap, args);
+    }
+
+    let first = load_trace(&first_name)?;
+    let second = load_trace(&second_name)?;
+    let divergence = match trace::first_divergence(&first, &second, !ignore_timers)
+    {
+        Some(divergence) => divergence,
+        None =>
+        {
+            println!("Traces match");
+            return Ok(());
+        }
+    };
+    let line = |entries: &[TraceEntry], position: usize|
+        entries.get(position).map_or("end of trace".to_string(), |entry| format!("line {}", entry.line));
+    println!("Traces diverge after {} matching instructions, at {} of {} and {} of {}: {}",
+                divergence.matching, line(&first, divergence.a), first_name,
+                line(&second, divergence.b), second_name, divergence.differences.join(", "));
+    // The instructions before are the same in both, but not their cycles
+    for entry in first[divergence.a.saturating_sub(context)..divergence.a].iter()
+    {
+        println!("  {}", entry.text);
+    }
+    for (marker, entries, position) in [("<", &first, divergence.a), (">", &second, divergence.b)].iter()
+    {
+        for entry in entries.iter().skip(*position).take(context + 1)
+        {
+            println!("{} {}", marker, entry.text);
+        }
+    }
+    bail!("Traces diverge");
+}
+
+// A player that can't be started only leaves the program silent, but a WAV file
+// that can't be written is an error. Headless runs go faster than real time, so
+// their sound is only recorded, never played
+fn create_audio(mute: bool, headless: bool, audio_command: &str, wav_name: &str,
+                tone: Tone) -> Result<Option<Box<dyn Buzzer>>>
+{
+    if mute
+    {
+        return Ok(None);
+    }
+    if !wav_name.is_empty()
+    {
+        let sink = WavSink::new(wav_name).chain_err(|| "Error creating WAV file")?;
+        return Ok(Some(Box::new(Audio::new(sink, tone))));
+    }
+    if headless
+    {
+        return Ok(Some(Box::new(Audio::new(NullSink, tone))));
+    }
+    match CommandSink::new(audio_command)
+    {
+        Ok(sink) => Ok(Some(Box::new(Audio::new(sink, tone)))),
+        Err(e) =>
+        {
+            eprintln!("No sound: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+// Runs without a window and as fast as possible, for regression tests. The last
+// frame is saved even when the rom faults, which still fails the run
+fn run_headless(chip: &mut Chip8, runner: &mut Runner, frames: usize, audio: Option<Box<dyn Buzzer>>,
+                dump_name: &str, dump_every: usize) -> Result<()>
+{
+    let mut display = HeadlessDisplay::new(frames);
+    if dump_every > 0
+    {
+        display.dump_every(dump_every, dump_name);
+    }
+    let buzzer = audio.unwrap_or_else(|| Box::new(NullBuzzer));
+    let mut frontend = Composite { display, keypad: NullKeypad, buzzer };
+    let result = runner.run_with_clock(chip, &mut frontend, &mut VirtualClock::new());
+    if !dump_name.is_empty() && dump_every == 0
+    {
+        image::save(chip.screen(), dump_name).chain_err(|| "Error saving the screen")?;
+    }
+    result
+}
+
+fn run_rom(args: Vec<String>) -> Result<()>
+{
+    let mut rom_name = String::new();
+    let mut variant_name = "chip8".to_string();
+    let mut quirks_name = String::new();
+    let mut rewind_seconds = 10usize;
+    let mut debug = false;
+    let mut seed: Option<u32> = None;
+    let mut rng_name = "xorshift".to_string();
+    let mut ips: Option<usize> = None;
+    let mut cycles_per_frame: Option<usize> = None;
+    let mut mute = false;
+    let mut audio_command = audio::DEFAULT_AUDIO_COMMAND.to_string();
+    let mut wav_name = String::new();
+    let mut tone = Tone::default();
+    let mut volume = 25u8;
+    let mut waveform_name = "square".to_string();
+    let mut headless = false;
+    let mut frames: Option<usize> = None;
+    let mut dump_name = String::new();
+    let mut dump_every = 0usize;
+    let mut input_name = String::new();
+    let mut record_name = String::new();
+    let mut play_name = String::new();
+    let mut keymap_name = String::new();
+    let mut fault_policies_text = String::new();
+    let mut crash_report_name = String::new();
+    let mut trace_name = String::new();
+    let mut trace_addresses = String::new();
+    let mut trace_cycles = String::new();
+    {
+        // this block limits scope of borrows by ap.refer() method
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Chip-8 interpreter by Satore");
+        ap.refer(&mut rom_name)
+            .add_argument("ROM", Store,
+                "File containing the rom").required();
+        ap.refer(&mut variant_name)
+            .add_option(&["-m", "--mode"], Store,
+                "Instruction set: chip8, schip or xochip");
+        ap.refer(&mut quirks_name)
+            .add_option(&["-q", "--quirks"], Store,
+                "Quirks preset: default, vip, chip48, schip or xochip, defaults to the usual ones of the mode");
+        ap.refer(&mut rewind_seconds)
+            .add_option(&["-r", "--rewind"], Store,
+                "Seconds that can be rewound holding backspace, 0 disables rewinding");
+        ap.refer(&mut debug)
+            .add_option(&["-d", "--debug"], StoreTrue,
+                "Start stopped in the debugger, controlled from the terminal");
+        ap.refer(&mut seed)
+            .add_option(&["-s", "--seed"], StoreOption,
+                "Seed of the random numbers, the same seed gives the same run");
+        ap.refer(&mut rng_name)
+            .add_option(&["--rng"], Store,
+                "Random number generator: xorshift or vip (the poorer one of the COSMAC VIP interpreter)");
+        ap.refer(&mut ips)
+            .add_option(&["--ips"], StoreOption,
+                "Instructions executed per second, 960 by default");
+        ap.refer(&mut cycles_per_frame)
+            .add_option(&["-c", "--cycles-per-frame"], StoreOption,
+                "Instructions executed per frame, at 60 frames per second");
+        ap.refer(&mut mute)
+            .add_option(&["--mute"], StoreTrue,
+                "Don't play any sound");
+        ap.refer(&mut audio_command)
+            .add_option(&["--audio-command"], Store,
+                "Program playing the sound, which receives 44100Hz mono 16 bit samples on its input");
+        ap.refer(&mut wav_name)
+            .add_option(&["--wav"], Store,
+                "Record the sound to a WAV file instead of playing it");
+        ap.refer(&mut tone.frequency)
+            .add_option(&["--frequency"], Store,
+                "Frequency of the buzzer in Hz, 440 by default");
+        ap.refer(&mut volume)
+            .add_option(&["--volume"], Store,
+                "Volume of the buzzer from 0 to 100, 25 by default");
+        ap.refer(&mut waveform_name)
+            .add_option(&["--waveform"], Store,
+                "Waveform of the buzzer: square, triangle, sawtooth or sine");
+        ap.refer(&mut input_name)
+            .add_option(&["-i", "--input"], Store,
+                "File with the keys pressed on every frame, used instead of the keyboard");
+        ap.refer(&mut keymap_name)
+            .add_option(&["-k", "--keymap"], Store,
+                "File with the key bindings, ~/.config/chip8/keymap.cfg by default");
+        ap.refer(&mut fault_policies_text)
+            .add_option(&["--on-fault"], Store,
+                "What to do when the rom crashes: halt (by default), debug, skip or wrap (memory and pc \
+                 faults only), for every fault or by kind, like opcode=skip,memory=wrap (kinds: stack, \
+                 opcode, memory, key, pc)");
+        ap.refer(&mut crash_report_name)
+            .add_option(&["--crash-report"], Store,
+                "File the crash report is written to when the rom halts on a fault, ROM.crash by default");
+        ap.refer(&mut trace_name)
+            .add_option(&["--trace"], Store,
+                "Log every instruction executed to a file, with the registers it changed");
+        ap.refer(&mut trace_addresses)
+            .add_option(&["--trace-addresses"], Store,
+                "Only trace the instructions in a range of addresses, in hexadecimal: 200-2FF");
+        ap.refer(&mut trace_cycles)
+            .add_option(&["--trace-cycles"], Store,
+                "Only trace the instructions executed in a range of cycles: 1000-2000, 5000-");
+        ap.refer(&mut record_name)
+            .add_option(&["--record"], Store,
+                "Record the keys of every frame to a movie file");
+        ap.refer(&mut play_name)
+            .add_option(&["--play"], Store,
+                "Play a movie back with the settings it was recorded with, checking it doesn't desync");
+        ap.refer(&mut headless)
+            .add_option(&["--headless"], StoreTrue,
+                "Run without a window as fast as possible, for a number of frames");
+        ap.refer(&mut frames)
+            .add_option(&["--frames"], StoreOption,
+                "Frames run with --headless, at 60 per second, 600 or the length of the movie by default");
+        ap.refer(&mut dump_name)
+            .add_option(&["--dump"], Store,
+                "Image file (png, pbm or ppm) the last frame of --headless is saved to");
+        ap.refer(&mut dump_every)
+            .add_option(&["--dump-every"], Store,
+                "Save every N frames to the --dump file, numbered with the frame");
+        parse_or_exit(&ap, args);
+    }
+
+    let mut variant = parse_variant(&variant_name)?;
+    let mut quirks = match Quirks::from_name(&quirks_name)
+    {
+        Some(quirks) => quirks,
+        None if quirks_name.is_empty() => variant.default_quirks(),
+        None => bail!(format!("Unknown quirks preset {}, expected one of: {}",
+                                quirks_name, chip8::quirks::PRESET_NAMES.join(", "))),
+    };
+    let mut rng_kind = match RngKind::from_name(&rng_name)
+    {
+        Some(rng_kind) => rng_kind,
+        None => bail!(format!("Unknown random number generator {}, expected one of: {}",
+                                rng_name, chip8::rng::RNG_NAMES.join(", "))),
+    };
+    let scheduler = match (ips, cycles_per_frame)
+    {
+        (Some(_), Some(_)) => bail!("--ips and --cycles-per-frame can't be used together"),
+        (Some(ips), None) => Scheduler::with_ips(ips),
+        (None, Some(cycles_per_frame)) => Scheduler::new(cycles_per_frame),
+        (None, None) => Scheduler::default(),
+    };
+    tone.volume = volume.min(100) as f32 / 100.0;
+    tone.waveform = match Waveform::from_name(&waveform_name)
+    {
+        Some(waveform) => waveform,
+        None => bail!(format!("Unknown waveform {}, expected one of: {}",
+                                waveform_name, audio::WAVEFORM_NAMES.join(", "))),
+    };
+    if !dump_name.is_empty() && ImageFormat::from_path(&dump_name).is_none()
+    {
+        bail!(format!("Unknown image format of {}, expected png, pbm or ppm", dump_name));
+    }
+    if (dump_every > 0 || !dump_name.is_empty()) && !headless
+    {
+        bail!("--dump and --dump-every need --headless");
+    }
+    if dump_every > 0 && dump_name.is_empty()
+    {
+        bail!("--dump-every needs --dump");
+    }
+    let fault_policies = FaultPolicies::parse(&fault_policies_text).chain_err(|| "Invalid --on-fault")?;
+    if (!trace_addresses.is_empty() || !trace_cycles.is_empty()) && trace_name.is_empty()
+    {
+        bail!("--trace-addresses and --trace-cycles need --trace");
+    }
+    if !play_name.is_empty() && (!input_name.is_empty() || !record_name.is_empty())
+    {
+        bail!("--play can't be used with --input or --record");
+    }
+    let mut seed = seed.unwrap_or_else(rand::random);
+    let rom = load_rom(rom_name.clone()).chain_err(|| "Error loading rom")?;
+    let mut player = None;
+    let mut movie_frames = None;
+    if !play_name.is_empty()
+    {
+        let movie = Movie::load(&play_name)?;
+        let header = movie.header;
+        if header.rom_hash != crc32(&rom)
+        {
+            bail!(format!("{} was recorded with a different rom", play_name));
+        }
+        variant = header.variant;
+        quirks = header.quirks;
+        rng_kind = header.rng_kind;
+        seed = header.seed;
+        movie_frames = Some(movie.len());
+        player = Some(MoviePlayer::new(movie));
+    }
+    let mut chip = Chip8::new(Some(&rom), variant, quirks).chain_err(|| "Error creating Chip8 struct")?;
+    chip.set_rng(Rng::new(rng_kind, seed));
+    let mut runner = Runner::new();
+    runner.scheduler = scheduler;
+    runner.fault_policies = fault_policies;
+    // Going back in time would break the movie
+    if player.is_none() && record_name.is_empty()
+    {
+        runner.save_slots = Some(SaveSlots::new(&rom_name));
+        if rewind_seconds > 0
+        {
+            runner.rewind = Some(Rewind::with_seconds(rewind_seconds));
+        }
+    }
+    runner.player = player;
+    if !record_name.is_empty()
+    {
+        runner.recorder = Some(MovieRecorder::new(MovieHeader::new(&rom, variant, quirks, rng_kind, seed)));
+    }
+    if !input_name.is_empty()
+    {
+        runner.input = Some(InputScript::load(&input_name)?);
+    }
+    if !trace_name.is_empty()
+    {
+        let mut tracer = Tracer::create(&trace_name)?;
+        if !trace_addresses.is_empty()
+        {
+            tracer.set_addresses(&trace_addresses)?;
+        }
+        if !trace_cycles.is_empty()
+        {
+            tracer.set_cycles(&trace_cycles)?;
+        }
+        runner.tracer = Some(tracer);
+    }
+    if debug
+    {
+        println!("Random seed {}", seed);
+        runner.debugger = Some(Debugger::new());
+    }
+    let audio = create_audio(mute, headless, &audio_command, &wav_name, tone)?;
+    let result = if headless
+    {
+        run_headless(&mut chip, &mut runner, headless::frame_count(frames, movie_frames), audio, &dump_name, dump_every)
+    }
+    else
+    {
+        // Only the keyboard uses the keymap, the default file is optional
+        let keymap_path = if keymap_name.is_empty()
+        {
+            keymap::default_config_path().filter(|path| path.exists())
+        }
+        else
+        {
+            Some(PathBuf::from(&keymap_name))
+        };
+        let keymap = match keymap_path
+        {
+            Some(path) => Some(KeymapConfig::load(&path)?.keymap(&rom_name, &rom)),
+            None => None,
+        };
+        let mut window = WindowFrontend::new().chain_err(|| "Error creating window")?;
+        if let Some(keymap) = keymap
+        {
+            window.set_keymap(keymap);
+        }
+        if let Some(audio) = audio
+        {
+            window.set_audio(audio);
+        }
+        runner.run(&mut chip, &mut window)
+    };
+    if let Err(ErrorKind::Fault(fault)) = result.as_ref().map_err(Error::kind)
+    {
+        let path = if crash_report_name.is_empty() { crash::report_path(&rom_name) }
+                   else { PathBuf::from(&crash_report_name) };
+        crash::write_report(&path, &chip, fault, &rom_name)?;
+        eprintln!("Crash report written to {}", path.display());
+    }
+    // Also saved when the rom crashes, to reproduce it
+    if let Some(recorder) = runner.recorder.take()
+    {
+        recorder.into_movie().save(&record_name).chain_err(|| "Error saving movie")?;
+    }
+    result.chain_err(|| "Error executing rom")
+}